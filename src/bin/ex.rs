@@ -127,6 +127,8 @@ async fn main() {
 mod tests {
     use super::*;
 
+    derive_aktor::actor_system!();
+
     #[derive(Default)]
     pub struct Ping {
         self_actor: Option<PingActor>
@@ -152,6 +154,480 @@ mod tests {
         }
     }
 
+    #[derive(Default)]
+    pub struct Greeter {
+        self_actor: Option<GreeterActor>
+    }
+
+    #[derive_actor(interface = "GreeterApi", broadcast, pool)]
+    impl Greeter {
+        pub fn greet(&self, #[actor(shard_key, into)] name: String) -> String {
+            format!("hello {}", name)
+        }
+
+        pub fn shout(&self, #[actor(shard_key)] name: &str) -> String {
+            format!("HELLO {}", name.to_uppercase())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Batcher {
+        self_actor: Option<BatcherActor>,
+        batch_lens: Vec<usize>,
+        notes: Vec<u32>,
+        cancelled: bool,
+    }
+
+    #[derive_actor(max_batch_size = 8)]
+    impl Batcher {
+        pub fn record(&mut self, n: u32) -> u32 {
+            n
+        }
+
+        pub fn batch_lens(&self) -> Vec<usize> {
+            self.batch_lens.clone()
+        }
+
+        pub fn note(&mut self, n: u32) {
+            self.notes.push(n);
+        }
+
+        pub fn note_later(&mut self, ctx: &BatcherContext, ns: Vec<u32>) -> usize {
+            for n in ns {
+                ctx.stash(BatcherMessage::note { n }).ok().unwrap();
+            }
+            ctx.stash_len()
+        }
+
+        pub fn release(&mut self, ctx: &BatcherContext) -> usize {
+            ctx.unstash_all();
+            ctx.stash_len()
+        }
+
+        pub fn notes(&self) -> Vec<u32> {
+            self.notes.clone()
+        }
+
+        pub fn sender(&self, ctx: &BatcherContext) -> Option<Option<uuid::Uuid>> {
+            ctx.envelope().map(|envelope| envelope.sender_id)
+        }
+
+        pub async fn ask_sender(&self, other: BatcherActor) -> Result<Option<Option<uuid::Uuid>>, BatcherActorError> {
+            other.sender().await
+        }
+
+        pub async fn ask_self(&self) -> Result<Vec<usize>, BatcherActorError> {
+            self.self_actor.as_ref().unwrap().batch_lens().await
+        }
+
+        pub async fn wait(&mut self, ctx: &BatcherContext, delay_ms: u64) -> bool {
+            tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+            self.cancelled = ctx.is_cancelled();
+            self.cancelled
+        }
+
+        pub fn cancelled(&self) -> bool {
+            self.cancelled
+        }
+
+        #[allow(clippy::boxed_local)]
+        pub fn finish(self: Box<Self>) -> Vec<usize> {
+            self.batch_lens
+        }
+
+        async fn handle_batch(&mut self, msgs: Vec<(BatcherEnvelope, BatcherMessage)>) {
+            self.batch_lens.push(msgs.len());
+            for msg in msgs {
+                aktors::actor::Actor::route_message(self, msg).await;
+            }
+        }
+    }
+
+    // Only the actor's own context is supplied by the router
+    #[derive(Clone)]
+    pub struct RequestContext {
+        n: u64,
+    }
+
+    #[derive(Default)]
+    pub struct Summer {
+        self_actor: Option<SummerActor>,
+        total: u64,
+    }
+
+    #[derive_actor]
+    impl Summer {
+        pub fn add(&mut self, #[actor(stream)] n: u64) {
+            self.total += n;
+        }
+
+        pub fn add_pair(&mut self, (x, y): (u64, u64)) {
+            self.total += x + y;
+        }
+
+        pub fn add_all(&mut self, mut ns: Vec<u64>) -> u64 {
+            ns.dedup();
+            self.total += ns.iter().sum::<u64>();
+            self.total
+        }
+
+        pub fn total(&self) -> u64 {
+            self.total
+        }
+
+        pub fn describe(&self, unit: &'static str) -> String {
+            format!("{} {}", self.total, unit)
+        }
+
+        pub fn add_request(&mut self, request: &RequestContext) -> u64 {
+            self.total += request.n;
+            self.total
+        }
+
+        pub fn countdown(&self) -> impl futures::Stream<Item = u64> {
+            futures::stream::iter((0..=self.total).rev())
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Counter {
+        self_actor: Option<WeakCounterActor>,
+        count: u64,
+    }
+
+    #[derive_actor(system)]
+    impl Counter {
+        #[actor(constructor)]
+        pub fn starting_at(count: u64) -> Self {
+            Self { self_actor: None, count }
+        }
+
+        pub fn limit() -> u64 {
+            u64::MAX
+        }
+
+        pub fn incr(&mut self) {
+            self.count += 1;
+        }
+
+        pub fn count(&self) -> u64 {
+            self.count
+        }
+
+        pub fn fail(&self) {
+            panic!("counter failed");
+        }
+
+        pub fn finish(self) -> u64 {
+            self.count
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Account {
+        self_actor: Option<AccountActor>,
+        balance: u64,
+        frozen: bool,
+    }
+
+    #[derive_actor(groups(admin))]
+    impl Account {
+        pub fn deposit(&mut self, amount: u64) -> u64 {
+            if !self.frozen {
+                self.balance += amount;
+            }
+            self.balance
+        }
+    }
+
+    use account_admin::AccountAdminMessage;
+
+    mod account_admin {
+        use super::*;
+
+        #[derive_actor(group = "admin")]
+        impl Account {
+            pub fn freeze(&mut self, frozen: bool) -> bool {
+                std::mem::replace(&mut self.frozen, frozen)
+            }
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Connection {
+        self_actor: Option<ConnectionActor>,
+        sent: Vec<String>,
+        unhandled: Vec<&'static str>,
+    }
+
+    #[derive_actor(states(Connecting, Connected), max_stash_size = 1)]
+    impl Connection {
+        pub fn current_state(&self, ctx: &ConnectionContext) -> ConnectionState {
+            ctx.state()
+        }
+
+        pub fn unhandled_messages(&self) -> Vec<&'static str> {
+            self.unhandled.clone()
+        }
+
+        // Writes wait for the connection, as long as there's room in the stash
+        fn unhandled(&mut self, ctx: &mut ConnectionContext, message: ConnectionMessage) {
+            let message = match message {
+                ConnectionMessage::Connected(ConnectionConnectedMessage::write { .. }) => match ctx.stash(message) {
+                    Ok(()) => return,
+                    Err(message) => message,
+                },
+                message => message,
+            };
+            self.unhandled.push(message.method_name());
+        }
+    }
+
+    #[derive_actor(state = "Connecting")]
+    impl Connection {
+        pub fn connected(&mut self, ctx: &mut ConnectionContext) {
+            ctx.become_state(ConnectionState::Connected);
+            ctx.unstash_all();
+        }
+    }
+
+    #[derive_actor(state = "Connected")]
+    impl Connection {
+        pub fn write(&mut self, data: String) -> usize {
+            self.sent.push(data);
+            self.sent.len()
+        }
+
+        pub fn disconnected(&mut self, ctx: &mut ConnectionContext) {
+            ctx.become_state(ConnectionState::Connecting);
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Ticker {
+        self_actor: Option<TickerActor>,
+        ticks: u32,
+        timer: Option<futures::future::AbortHandle>,
+    }
+
+    #[derive_actor]
+    impl Ticker {
+        pub fn start(&mut self, ctx: &mut TickerContext, period_ms: u64) {
+            let period = Duration::from_millis(period_ms);
+            self.timer = Some(ctx.send_interval(period, || TickerMessage::tick {}));
+        }
+
+        pub fn remind(&self, ctx: &TickerContext, delay_ms: u64) {
+            ctx.send_after(Duration::from_millis(delay_ms), TickerMessage::tick {});
+        }
+
+        pub fn tick(&mut self) {
+            self.ticks += 1;
+            if self.ticks == 3 {
+                self.timer.take().unwrap().abort();
+            }
+        }
+
+        pub fn ticks(&self) -> u32 {
+            self.ticks
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Library {
+        self_actor: Option<LibraryActor>,
+        books: u64,
+        readers: AtomicUsize,
+        most_readers: AtomicUsize,
+        browsed: std::sync::Mutex<Vec<u32>>,
+    }
+
+    #[derive_actor(max_concurrency = 2)]
+    impl Library {
+        #[actor(concurrent)]
+        pub async fn read(&self, delay_ms: u64) -> u64 {
+            let readers = self.readers.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_readers.fetch_max(readers, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+            self.readers.fetch_sub(1, Ordering::SeqCst);
+            self.books
+        }
+
+        pub fn add_book(&mut self) -> usize {
+            self.books += 1;
+            self.readers.load(Ordering::SeqCst)
+        }
+
+        pub fn most_readers(&self) -> usize {
+            self.most_readers.load(Ordering::SeqCst)
+        }
+
+        #[actor(concurrent)]
+        pub async fn browse(&self, n: u32) {
+            self.browsed.lock().unwrap().push(n);
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+
+        pub fn browse_later(&mut self, ctx: &LibraryContext, ns: Vec<u32>) -> usize {
+            for n in ns {
+                ctx.stash(LibraryMessage::browse { n }).ok().unwrap();
+            }
+            ctx.stash_len()
+        }
+
+        pub fn open(&mut self, ctx: &LibraryContext) {
+            ctx.unstash_all();
+        }
+
+        pub fn browsed(&self) -> Vec<u32> {
+            self.browsed.lock().unwrap().clone()
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Warmup {
+        self_actor: Option<WarmupActor>,
+        ready: bool,
+    }
+
+    #[derive_actor]
+    impl Warmup {
+        // Requests wait until the actor is ready, unless the stash is full
+        fn intercept(&self, ctx: &WarmupContext, message: WarmupMessage) -> Option<WarmupMessage> {
+            match message {
+                WarmupMessage::is_ready { .. } if !self.ready => ctx.stash(message).err(),
+                message => Some(message),
+            }
+        }
+
+        pub fn ready(&mut self, ctx: &WarmupContext) {
+            self.ready = true;
+            ctx.unstash_all();
+        }
+
+        pub fn is_ready(&self) -> bool {
+            self.ready
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Node {
+        self_actor: Option<NodeActor>,
+        peer: Option<WeakNodeActor>,
+    }
+
+    #[derive_actor(system)]
+    impl Node {
+        pub fn set_peer(&mut self, peer: WeakNodeActor) {
+            self.peer = Some(peer);
+        }
+
+        pub fn answer(&self) -> u32 {
+            42
+        }
+
+        pub async fn ask_self(&self) -> Result<u32, NodeActorError> {
+            self.self_actor.as_ref().unwrap().answer().await
+        }
+
+        pub async fn ask_peer(&self) -> Result<u32, NodeActorError> {
+            let peer = self.peer.as_ref().and_then(|peer| peer.upgrade()).unwrap();
+            peer.relay().await.and_then(|answer| answer)
+        }
+
+        pub async fn relay(&self) -> Result<u32, NodeActorError> {
+            let peer = self.peer.as_ref().and_then(|peer| peer.upgrade()).unwrap();
+            peer.answer().await
+        }
+
+        pub async fn ask_relay(&self, relay: RelayActor) -> Result<Result<u32, NodeActorError>, RelayActorError> {
+            relay.relay(self.self_actor.clone().unwrap()).await
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Relay {
+        self_actor: Option<RelayActor>,
+    }
+
+    #[derive_actor(system)]
+    impl Relay {
+        pub async fn relay(&self, node: NodeActor) -> Result<u32, NodeActorError> {
+            node.answer().await
+        }
+
+        pub async fn poke(&self, worker: WorkerActor) {
+            worker.record_sender().await;
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Parser {
+        self_actor: Option<ParserActor>,
+        parsed: Vec<u32>,
+        restarts: u32,
+    }
+
+    #[derive_actor]
+    impl Parser {
+        pub fn parse(&mut self, input: String) -> Result<(), std::num::ParseIntError> {
+            self.parsed.push(input.parse()?);
+            Ok(())
+        }
+
+        pub fn report(&self) -> (Vec<u32>, u32) {
+            (self.parsed.clone(), self.restarts)
+        }
+
+        // The first error restarts the parser, the second one stops it
+        fn on_handler_error(&mut self, method: &'static str, error: &(dyn Any + Send)) -> ParserErrorPolicy {
+            assert_eq!(method, "parse");
+            assert!(error.is::<std::num::ParseIntError>());
+            if self.restarts > 0 {
+                return ParserErrorPolicy::Stop;
+            }
+            ParserErrorPolicy::Restart(Parser { restarts: self.restarts + 1, ..Parser::default() })
+        }
+    }
+
+    #[derive(Default)]
+    pub struct Worker {
+        self_actor: Option<WorkerActor>,
+        jobs: u32,
+        saw_cancel: bool,
+        sender_id: Option<uuid::Uuid>,
+    }
+
+    #[derive_actor(system)]
+    impl Worker {
+        pub async fn slow_job(&mut self, ctx: &WorkerContext, delay_ms: u64) -> u32 {
+            tokio::time::delay_for(Duration::from_millis(delay_ms)).await;
+            self.saw_cancel = ctx.is_cancelled();
+            self.job()
+        }
+
+        pub fn job(&mut self) -> u32 {
+            self.jobs += 1;
+            self.jobs
+        }
+
+        pub fn report(&self) -> (u32, bool) {
+            (self.jobs, self.saw_cancel)
+        }
+
+        pub fn record_sender(&mut self, ctx: &WorkerContext) {
+            self.sender_id = ctx.envelope().unwrap().sender_id;
+        }
+
+        pub async fn poke_self(&self) {
+            self.self_actor.as_ref().unwrap().record_sender().await;
+        }
+
+        pub fn sender_id(&self) -> Option<uuid::Uuid> {
+            self.sender_id
+        }
+    }
+
     #[tokio::test]
     async fn test_termination() {
         let (ping, ping_handle) = PingActor::new(Ping::default()).await;
@@ -170,4 +646,598 @@ mod tests {
         pong_handle.await;
         ping_handle.await;
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_request_response() {
+        let (greeter, greeter_handle) = GreeterActor::new(Greeter::default()).await;
+        assert_eq!(greeter.greet("world".to_owned()).await.unwrap(), "hello world");
+        assert_eq!(greeter.greet("world").await.unwrap(), "hello world");
+        assert_eq!(greeter.shout("world").await.unwrap(), "HELLO WORLD");
+        drop(greeter);
+        greeter_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (greeter, mut probe) = GreeterActor::probe();
+        probe.on_greet(|name| format!("scripted {}", name));
+
+        assert_eq!(greeter.greet("world".to_owned()).await.unwrap(), "scripted world");
+
+        match probe.recv().await {
+            Some(GreeterMessage::greet { name, .. }) => assert_eq!(name, "world"),
+            _ => panic!("Expected a greet message"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_interface() {
+        async fn greet_world(greeter: &impl GreeterApi) -> String {
+            greeter.greet("world".to_owned()).await.unwrap()
+        }
+
+        let (greeter, greeter_handle) = GreeterActor::new(Greeter::default()).await;
+        assert_eq!(greet_world(&greeter).await, "hello world");
+        assert_eq!(greet_world(&tokio::sync::Mutex::new(Greeter::default())).await, "hello world");
+        drop(greeter);
+        greeter_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_handle_batch() {
+        let (batcher, batcher_handle) = BatcherActor::new(Batcher::default()).await;
+
+        let records = (0..20).map(|n| batcher.record(n));
+        futures::future::join_all(records).await;
+
+        let batch_lens = batcher.batch_lens().await.unwrap();
+        // Every record, plus the batch_lens call itself
+        assert_eq!(batch_lens.iter().sum::<usize>(), 21);
+        assert!(batch_lens.iter().all(|&len| len <= 8));
+        assert!(batch_lens.iter().any(|&len| len > 1));
+
+        // Unstashed messages are batched ahead of the rest of the mailbox
+        assert_eq!(batcher.note_later(vec![1, 2, 3]).await.unwrap(), 3);
+        assert_eq!(batcher.release().await.unwrap(), 0);
+        batcher.send(BatcherMessage::note { n: 4 }).await.unwrap();
+        assert_eq!(batcher.notes().await.unwrap(), vec![1, 2, 3, 4]);
+
+        // Batched messages are handled in their envelope
+        assert_eq!(batcher.sender().await.unwrap(), Some(None));
+        assert_eq!(batcher.ask_self().await.unwrap(), Err(BatcherActorError::Deadlock));
+        assert!(tokio::time::timeout(Duration::from_millis(5), batcher.wait(30)).await.is_err());
+        assert!(batcher.cancelled().await.unwrap());
+
+        // A batched message sent by another actor records it as the sender
+        let (other, other_handle) = BatcherActor::new(Batcher::default()).await;
+        let other_id = other.ask_sender(batcher.clone()).await.unwrap().unwrap();
+        assert!(matches!(other_id, Some(Some(_))));
+        assert_eq!(other.ask_sender(batcher.clone()).await.unwrap().unwrap(), other_id);
+        assert_ne!(batcher.ask_sender(other.clone()).await.unwrap().unwrap(), other_id);
+        drop(other);
+        other_handle.await;
+
+        drop(batcher);
+        batcher_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_streams() {
+        use futures::StreamExt;
+
+        let (summer, summer_handle) = SummerActor::new(Summer::default()).await;
+
+        summer.add(futures::stream::iter(vec![1, 2])).await;
+        while summer.total().await.unwrap() != 3 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        let countdown: Vec<u64> = summer.countdown().await.collect().await;
+        assert_eq!(countdown, vec![3, 2, 1, 0]);
+
+        drop(summer);
+        summer_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_patterns() {
+        let (summer, summer_handle) = SummerActor::new(Summer::default()).await;
+
+        summer.send(SummerAddPairArgs { arg_1: (1, 2) }.into()).await.unwrap();
+        assert_eq!(summer.add_all(vec![3, 3]).await.unwrap(), 6);
+        assert_eq!(summer.add_request(&RequestContext { n: 4 }).await.unwrap(), 10);
+        assert_eq!(summer.describe("apples").await.unwrap(), "10 apples");
+
+        drop(summer);
+        summer_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_sink() {
+        use futures::StreamExt;
+
+        let (summer, summer_handle) = SummerActor::new(Summer::default()).await;
+
+        summer.send(SummerMessage::add { n: 1 }).await.unwrap();
+
+        futures::stream::iter(vec![SummerAddArgs { n: 2 }, SummerAddArgs { n: 3 }])
+            .map(|args| Ok(SummerMessage::from(args)))
+            .forward(summer.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(summer.total().await.unwrap(), 6);
+
+        drop(summer);
+        summer_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_handlers() {
+        let (library, library_handle) = LibraryActor::new(Library::default()).await;
+
+        let reads = futures::future::join_all((0..4).map(|_| library.read(30))).await;
+        assert!(reads.into_iter().all(|books| books.unwrap() == 0));
+        assert_eq!(library.most_readers().await.unwrap(), 2);
+
+        // Writers wait for the readers ahead of them, and readers behind them wait for the writer
+        let (first, readers, second) = futures::join!(library.read(30), library.add_book(), library.read(0));
+        assert_eq!(readers.unwrap(), 0);
+        assert!(first.unwrap() <= second.unwrap());
+
+        // Unstashed concurrent messages still go before the rest of the mailbox. They were stashed by a
+        // request, but don't go away with its caller
+        assert_eq!(library.browse_later(vec![1, 2, 3]).await.unwrap(), 3);
+        library.send(LibraryMessage::open {}).await.unwrap();
+        library.send(LibraryMessage::browse { n: 4 }).await.unwrap();
+        assert_eq!(library.browsed().await.unwrap(), vec![1, 2, 3, 4]);
+
+        drop(library);
+        library_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_deadlocks() {
+        let (a, a_handle) = NodeActor::new(Node::default()).await;
+        let (b, b_handle) = NodeActor::new(Node::default()).await;
+
+        assert_eq!(a.ask_self().await.unwrap(), Err(NodeActorError::Deadlock));
+
+        a.send(NodeMessage::set_peer { peer: b.downgrade() }).await.unwrap();
+        b.send(NodeMessage::set_peer { peer: a.downgrade() }).await.unwrap();
+
+        // b -> a is fine, a -> b -> a would wait on itself
+        assert_eq!(b.relay().await.unwrap(), Ok(42));
+        assert_eq!(a.ask_peer().await.unwrap(), Err(NodeActorError::Deadlock));
+
+        drop(a);
+        drop(b);
+        a_handle.await;
+        b_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_cross_type_deadlocks() {
+        let (node, node_handle) = NodeActor::new(Node::default()).await;
+        let (relay, relay_handle) = RelayActor::new(Relay::default()).await;
+
+        // relay -> node is fine, node -> relay -> node would wait on itself
+        assert_eq!(relay.relay(node.clone()).await.unwrap(), Ok(42));
+        assert_eq!(node.ask_relay(relay.clone()).await.unwrap(), Ok(Err(NodeActorError::Deadlock)));
+
+        drop(node);
+        drop(relay);
+        node_handle.await;
+        relay_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_cancellation() {
+        let (worker, worker_handle) = WorkerActor::new(Worker::default()).await;
+
+        // Both callers give up while the slow job is running, so the queued job is skipped
+        let timeout = Duration::from_millis(10);
+        let (slow, queued) = futures::join!(
+            tokio::time::timeout(timeout, worker.slow_job(50)),
+            tokio::time::timeout(timeout, worker.job()),
+        );
+        assert!(slow.is_err() && queued.is_err());
+        assert_eq!(worker.report().await.unwrap(), (1, true));
+
+        assert_eq!(worker.slow_job(0).await.unwrap(), 2);
+        assert_eq!(worker.report().await.unwrap(), (2, false));
+
+        drop(worker);
+        worker_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_envelopes() {
+        let (worker, worker_handle) = WorkerActor::new(Worker::default()).await;
+
+        worker.send(WorkerMessage::poke_self {}).await.unwrap();
+        while worker.sender_id().await.unwrap().is_none() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        let own_id = worker.sender_id().await.unwrap();
+        worker.send(WorkerMessage::record_sender {}).await.unwrap();
+        assert_eq!(worker.sender_id().await.unwrap(), None);
+
+        // Actors of other types are senders too
+        let (relay, relay_handle) = RelayActor::new(Relay::default()).await;
+        relay.send(RelayMessage::poke { worker: worker.clone() }).await.unwrap();
+        while worker.sender_id().await.unwrap().is_none() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert_ne!(worker.sender_id().await.unwrap(), own_id);
+        drop(relay);
+        relay_handle.await;
+
+        // Messages past their deadline are dropped unhandled
+        let expired = worker.with_deadline(std::time::Instant::now());
+        assert_eq!(expired.job().await, Err(WorkerActorError::NoReply));
+        assert_eq!(worker.job().await.unwrap(), 1);
+
+        drop(expired);
+        drop(worker);
+        worker_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_timers() {
+        let (ticker, ticker_handle) = TickerActor::new(Ticker::default()).await;
+
+        // A zero period is clamped rather than panicking
+        ticker.start(0).await;
+        while ticker.ticks().await.unwrap() < 3 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // The interval was aborted on the third tick, though ticks already sent still arrive
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        let ticks = ticker.ticks().await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        assert_eq!(ticker.ticks().await.unwrap(), ticks);
+
+        ticker.remind(1).await;
+        while ticker.ticks().await.unwrap() <= ticks {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+
+        // A pending timer doesn't keep the actor alive
+        ticker.remind(60_000).await;
+        drop(ticker);
+        ticker_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_weak_handles() {
+        let (counter, counter_handle) = CounterActor::new(Counter::default()).await;
+        let weak_counter = counter.downgrade();
+
+        weak_counter.send(CounterMessage::incr {}).await.unwrap();
+        assert_eq!(weak_counter.upgrade().unwrap().count().await.unwrap(), 1);
+
+        // Neither the weak handle nor the weak `self_actor` keep the actor alive
+        drop(counter);
+        counter_handle.await;
+
+        assert!(weak_counter.upgrade().is_none());
+        assert_eq!(weak_counter.send(CounterMessage::incr {}).await, Err(CounterActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_join_handle() {
+        let (counter, counter_handle) = CounterActor::new(Counter::default()).await;
+        counter.send(CounterMessage::incr {}).await.unwrap();
+        drop(counter);
+
+        let (exit_reason, counter) = counter_handle.await;
+        assert!(exit_reason.is_normal());
+        assert_eq!(counter.unwrap().count, 1);
+
+        // A panic stops the actor even while it's still referenced
+        let (counter, counter_handle) = CounterActor::new(Counter::default()).await;
+        counter.send(CounterMessage::incr {}).await.unwrap();
+        counter.send(CounterMessage::fail {}).await.unwrap();
+
+        match counter_handle.await {
+            (CounterExitReason::Panicked(payload), Some(state)) => {
+                assert_eq!(payload.downcast_ref::<&str>(), Some(&"counter failed"));
+                assert_eq!(state.count, 1);
+            }
+            exit => panic!("Expected a panic, got {:?}", exit.0),
+        }
+        assert_eq!(counter.count().await, Err(CounterActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_constructors() {
+        let (counter, counter_handle) = CounterActor::spawn_starting_at(5).await;
+        assert_eq!(counter.count().await.unwrap(), 5);
+        assert_eq!(Counter::limit(), u64::MAX);
+
+        drop(counter);
+        counter_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_terminal_messages() {
+        let (counter, counter_handle) = CounterActor::new(Counter::default()).await;
+        counter.send(CounterMessage::incr {}).await.unwrap();
+        assert_eq!(counter.finish().await.unwrap(), 1);
+
+        // The actor stops even though `counter` is still alive, and its state went to `finish`
+        let (exit_reason, state) = counter_handle.await;
+        assert!(exit_reason.is_normal());
+        assert!(state.is_none());
+        assert_eq!(counter.count().await, Err(CounterActorError::Stopped));
+
+        let (batcher, batcher_handle) = BatcherActor::new(Batcher::default()).await;
+        let records = (0..4).map(|n| batcher.record(n));
+        let (_, batch_lens) = futures::join!(futures::future::join_all(records), batcher.finish());
+        assert!(batch_lens.unwrap().iter().sum::<usize>() <= 4);
+        batcher_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_actor_system() {
+        let system = ActorSystem::new();
+        let counter = system.spawn("counter", CounterActor::new(Counter::default())).await;
+        let worker = system.spawn("worker", WorkerActor::new(Worker::default())).await;
+
+        // Messages queued before the shutdown are still handled, though the handles are alive
+        counter.send(CounterMessage::incr {}).await.unwrap();
+        let (count, killed) = futures::join!(counter.count(), system.shutdown(Duration::from_secs(5)));
+        assert_eq!(count.unwrap(), 1);
+        assert!(killed.is_empty());
+        assert_eq!(counter.count().await, Err(CounterActorError::Stopped));
+        assert_eq!(worker.job().await, Err(WorkerActorError::Stopped));
+        system.join_all().await;
+
+        // An actor still busy at the timeout is killed
+        let system = ActorSystem::new();
+        let worker = system.spawn("worker", WorkerActor::new(Worker::default())).await;
+        let (job, killed) = futures::join!(worker.slow_job(60_000), system.shutdown(Duration::from_millis(20)));
+        assert_eq!(job, Err(WorkerActorError::NoReply));
+        assert_eq!(killed, vec!["worker".to_string()]);
+
+        // Actors that stopped are let go of when another is spawned, or when joining
+        let system = ActorSystem::new();
+        let counter = system.spawn("counter", CounterActor::new(Counter::default())).await;
+        counter.finish().await.unwrap();
+        let stopped = system.members()[0].stopped.clone();
+        stopped.await;
+        let worker = system.spawn("worker", WorkerActor::new(Worker::default())).await;
+        assert_eq!(system.members().len(), 1);
+        drop(worker);
+        system.join_all().await;
+        assert!(system.members().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_handler_errors() {
+        let (parser, parser_handle) = ParserActor::new(Parser::default()).await;
+
+        // A caller waiting on the result gets the error
+        assert!(parser.parse("x".to_owned()).await.unwrap().is_err());
+        assert_eq!(parser.report().await.unwrap(), (vec![], 0));
+
+        // Nobody waits on a message sent without a `reply_to`, so the error goes to `on_handler_error`
+        parser.send(ParserParseArgs { input: "1".to_owned() }.into()).await.unwrap();
+        parser.send(ParserMessage::parse { input: "x".to_owned(), reply_to: None }).await.unwrap();
+        assert_eq!(parser.report().await.unwrap(), (vec![], 1));
+
+        parser.send(ParserParseArgs { input: "2".to_owned() }.into()).await.unwrap();
+        parser.send(ParserParseArgs { input: "y".to_owned() }.into()).await.unwrap();
+        match parser_handle.await {
+            (ParserExitReason::Failed(error), Some(state)) => {
+                assert!(error.is::<std::num::ParseIntError>());
+                assert_eq!(state.parsed, vec![2]);
+            }
+            exit => panic!("Expected a failure, got {:?}", exit.0),
+        }
+        assert_eq!(parser.report().await, Err(ParserActorError::Stopped));
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let (counter, counter_handle) = CounterActor::new(Counter::default()).await;
+        let dead_letters = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = dead_letters.clone();
+        counter.set_dead_letters(move |dead_letter| {
+            let msg = dead_letter.message.downcast::<CounterMessage>().unwrap();
+            sink.lock().unwrap().push((dead_letter.method, msg.method_name()));
+        });
+
+        // `incr` is either left in the mailbox by `finish`, or sent after the actor stopped
+        let (count, _) = futures::join!(counter.finish(), counter.incr());
+        assert_eq!(count.unwrap(), 0);
+        counter_handle.await;
+        counter.incr().await;
+
+        while dead_letters.lock().unwrap().len() < 2 {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert_eq!(*dead_letters.lock().unwrap(), vec![("incr", "incr"); 2]);
+
+        // `system` actors without a sink of their own, or for their type, use the ActorSystem's
+        let (relay, relay_handle) = RelayActor::new(Relay::default()).await;
+        let (worker, worker_handle) = WorkerActor::new(Worker::default()).await;
+        let (batcher, batcher_handle) = BatcherActor::new(Batcher::default()).await;
+        let system_dead_letters = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = system_dead_letters.clone();
+        ActorSystem::set_default_dead_letters(move |dead_letter| {
+            let method = match dead_letter.message.downcast::<RelayMessage>() {
+                Ok(msg) => msg.method_name(),
+                Err(message) if message.is::<BatcherMessage>() => "batcher",
+                Err(_) => return,
+            };
+            sink.lock().unwrap().push((dead_letter.method, method));
+        });
+
+        // Other actors don't
+        batcher_handle.kill();
+        batcher_handle.await;
+        batcher.note(1).await;
+
+        relay_handle.kill();
+        relay_handle.await;
+        relay.poke(worker).await;
+        while system_dead_letters.lock().unwrap().is_empty() {
+            tokio::time::delay_for(Duration::from_millis(1)).await;
+        }
+        assert_eq!(*system_dead_letters.lock().unwrap(), vec![("poke", "poke")]);
+        worker_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_message_groups() {
+        let (account, account_handle) = AccountActor::new(Account::default()).await;
+
+        assert_eq!(account.deposit(5).await.unwrap(), 5);
+        assert!(!account.freeze(true).await.unwrap());
+        assert_eq!(account.deposit(5).await.unwrap(), 5);
+
+        drop(account);
+        account_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_states() {
+        let (connection, connection_handle) = ConnectionActor::new(Connection::default()).await;
+        assert_eq!(connection.current_state().await.unwrap(), ConnectionState::Connecting);
+
+        // `write` is only accepted once connected, until then `unhandled` stashes it
+        let (reply_to, early) = futures::channel::oneshot::channel();
+        let write = ConnectionConnectedMessage::write { data: "early".to_owned(), reply_to };
+        connection.send(write.into()).await.unwrap();
+        let (reply_to, overflow) = futures::channel::oneshot::channel();
+        let write = ConnectionConnectedMessage::write { data: "overflow".to_owned(), reply_to };
+        connection.send(write.into()).await.unwrap();
+
+        // The stashed write goes before the one behind `connected` in the mailbox
+        connection.send(ConnectionConnectingMessage::connected {}.into()).await.unwrap();
+        assert_eq!(connection.write("late".to_owned()).await.unwrap(), 2);
+        assert_eq!(early.await.unwrap(), 1);
+        assert!(overflow.await.is_err());
+        assert_eq!(connection.current_state().await.unwrap(), ConnectionState::Connected);
+
+        connection.send(ConnectionConnectingMessage::connected {}.into()).await.unwrap();
+        connection.send(ConnectionConnectedMessage::disconnected {}.into()).await.unwrap();
+        assert_eq!(connection.current_state().await.unwrap(), ConnectionState::Connecting);
+        assert_eq!(connection.unhandled_messages().await.unwrap(), vec!["write", "connected"]);
+
+        drop(connection);
+        connection_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_intercept() {
+        let (warmup, warmup_handle) = WarmupActor::new(Warmup::default()).await;
+
+        // The request is stashed, and answered once the actor is ready
+        let (ready, ()) = futures::join!(warmup.is_ready(), async {
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            warmup.ready().await;
+        });
+        assert_eq!(ready, Ok(true));
+
+        drop(warmup);
+        warmup_handle.await;
+
+        // A stashed request whose caller went away becomes a dead letter once unstashed
+        let (warmup, warmup_handle) = WarmupActor::new(Warmup::default()).await;
+        let dead_letters = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = dead_letters.clone();
+        warmup.set_dead_letters(move |dead_letter| sink.lock().unwrap().push(dead_letter.method));
+
+        assert!(tokio::time::timeout(Duration::from_millis(10), warmup.is_ready()).await.is_err());
+        warmup.ready().await;
+        assert_eq!(warmup.is_ready().await, Ok(true));
+        assert_eq!(*dead_letters.lock().unwrap(), vec!["is_ready"]);
+
+        drop(warmup);
+        warmup_handle.await;
+    }
+
+    #[tokio::test]
+    async fn test_broadcast() {
+        let (first, first_handle) = GreeterActor::new(Greeter::default()).await;
+        let (second, second_handle) = GreeterActor::new(Greeter::default()).await;
+
+        let greeters: GreeterActorGroup = vec![first.clone(), second.clone()].into_iter().collect();
+        assert_eq!(greeters.len(), 2);
+
+        let greetings = greeters.greet("world".to_owned()).await;
+        assert_eq!(greetings, vec![Ok("hello world".to_owned()), Ok("hello world".to_owned())]);
+
+        // The group doesn't keep its members alive
+        drop(first);
+        first_handle.await;
+        assert_eq!(greeters.len(), 1);
+
+        drop(second);
+        second_handle.await;
+        assert!(greeters.is_empty());
+
+        // An actor whose only handle went to the group stops, and calls skip it
+        let (third, third_handle) = GreeterActor::new(Greeter::default()).await;
+        greeters.join(third);
+        third_handle.await;
+        assert!(greeters.is_empty());
+        assert!(greeters.greet("world".to_owned()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_pool() {
+        let greeters = GreeterActorPool::new(3, Greeter::default).await;
+        assert_eq!(greeters.len(), 3);
+        assert_eq!(greeters.greet("world".to_owned()).await.unwrap(), "hello world");
+
+        greeters.set_strategy(GreeterPoolStrategy::LeastLoaded);
+        greeters.resize(1).await;
+        assert_eq!(greeters.len(), 1);
+        assert_eq!(greeters.greet("world".to_owned()).await.unwrap(), "hello world");
+
+        greeters.set_strategy(GreeterPoolStrategy::Random);
+        greeters.resize(2).await;
+        assert_eq!(greeters.len(), 2);
+        assert_eq!(greeters.greet("world".to_owned()).await.unwrap(), "hello world");
+
+        greeters.join().await;
+    }
+
+    #[tokio::test]
+    async fn test_shards() {
+        let mut handles = Vec::new();
+        let shards = GreeterActorShards::new();
+        for _ in 0..3 {
+            let (greeter, handle) = GreeterActor::new(Greeter::default()).await;
+            shards.add_shard(greeter);
+            handles.push(handle);
+        }
+
+        assert_eq!(shards.greet("world".to_owned()).await.unwrap(), "hello world");
+
+        let keys: Vec<String> = (0..100).map(|key| key.to_string()).collect();
+        let owner_of = |key: &String| shards.shard_for(key).unwrap().actor_uuid;
+        let owners: Vec<_> = keys.iter().map(owner_of).collect();
+
+        let removed = shards.shard_for(&keys[0]).unwrap();
+        shards.remove_shard(&removed);
+
+        // Only the keys owned by the removed shard move
+        for (key, owner) in keys.iter().zip(owners) {
+            if owner != removed.actor_uuid {
+                assert_eq!(owner_of(key), owner);
+            }
+        }
+
+        drop(removed);
+        drop(shards);
+        for handle in handles {
+            handle.await;
+        }
+    }
+}
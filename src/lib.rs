@@ -21,13 +21,169 @@ use syn::GenericParam;
 use syn::token::Comma;
 
 
+/// How many points each shard gets on the consistent hash ring
+const SHARD_VIRTUAL_NODES: u64 = 64;
+
+/// How many items of a returned stream may be in flight to the caller
+const STREAM_REPLY_CAPACITY: usize = 16;
+
+/// The methods every Actor handle has, which message methods can't be named after
+const RESERVED_METHODS: &[&str] = &[
+    "new", "probe", "send", "is_stopped", "with_deadline", "envelope", "set_dead_letters",
+    "set_default_dead_letters", "downgrade", "queue_len",
+];
+
+/// The methods of the `XActorGroup` generated for `broadcast`
+const GROUP_METHODS: &[&str] = &["new", "join", "len", "is_empty", "live_members"];
+
+/// The methods of the `XActorPool` generated for `pool`
+const POOL_METHODS: &[&str] = &["new", "set_strategy", "len", "is_empty", "resize", "join", "live_members", "pick"];
+
+/// The methods of the `XActorShards` generated for methods with a `#[actor(shard_key)]` argument
+const SHARDS_METHODS: &[&str] = &["new", "add_shard", "remove_shard", "len", "is_empty", "shard_for", "hash"];
+
+/// Generates an `ActorSystem`, which owns the actors declared with `#[derive_actor(system)]` so they
+/// can be joined or shut down together. Those actors also share a call chain and a default dead
+/// letter sink, and have to be declared where the generated items are in scope
+#[proc_macro]
+pub fn actor_system(input: TokenStream) -> TokenStream {
+    syn::parse_macro_input!(input as syn::parse::Nothing);
+    gen_actor_system().into_token_stream().into()
+}
+
+/// Arguments accepted by `#[derive_actor(..)]`
+#[derive(Default)]
+struct ActorArgs {
+    /// `on_error` - catch panics raised by message handlers
+    on_error: bool,
+    /// `interface = "Name"` - generate an async trait covering every message method
+    interface: Option<syn::Ident>,
+    /// `broadcast` - generate an `XActorGroup` that fans calls out to many actors
+    broadcast: bool,
+    /// `pool` - generate an `XActorPool` that routes each call to one of its members
+    pool: bool,
+    /// `max_batch_size = N` - most messages handed to `handle_batch` at once
+    max_batch_size: Option<usize>,
+    /// `max_batch_linger_ms = N` - how long to wait for a batch to fill up
+    max_batch_linger_ms: Option<u64>,
+    /// `max_concurrency = N` - most `#[actor(concurrent)]` handlers running at once
+    max_concurrency: Option<usize>,
+    /// `max_stash_size = N` - most messages `ctx.stash` holds at once
+    max_stash_size: Option<usize>,
+    /// `groups(a, b)` - the message groups declared by other impl blocks of this actor
+    groups: Vec<syn::Ident>,
+    /// `group = "a"` - this impl block declares a message group of the actor
+    group: Option<syn::Ident>,
+    /// `system` - the actor can be owned by the `ActorSystem` generated by `actor_system!()`, and shares
+    /// its call chain and dead letter sink
+    system: bool,
+    /// `states(A, B)` - the states declared by other impl blocks of this actor, it starts in the first
+    states: Vec<syn::Ident>,
+    /// `state = "A"` - this impl block declares the messages the actor only accepts in state `A`
+    state: Option<syn::Ident>,
+}
+
+impl ActorArgs {
+    fn parse(args: syn::AttributeArgs) -> syn::Result<Self> {
+        let mut actor_args = Self::default();
+
+        for arg in args {
+            match arg {
+                syn::NestedMeta::Meta(syn::Meta::Path(ref path)) if path.is_ident("on_error") => {
+                    actor_args.on_error = true;
+                }
+                syn::NestedMeta::Meta(syn::Meta::Path(ref path)) if path.is_ident("broadcast") => {
+                    actor_args.broadcast = true;
+                }
+                syn::NestedMeta::Meta(syn::Meta::Path(ref path)) if path.is_ident("pool") => {
+                    actor_args.pool = true;
+                }
+                syn::NestedMeta::Meta(syn::Meta::Path(ref path)) if path.is_ident("system") => {
+                    actor_args.system = true;
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("interface") => {
+                    match nv.lit {
+                        syn::Lit::Str(ref name) => actor_args.interface = Some(name.parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `interface = \"TraitName\"`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("max_batch_size") => {
+                    match nv.lit {
+                        syn::Lit::Int(ref size) => actor_args.max_batch_size = Some(size.base10_parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `max_batch_size = N`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("max_batch_linger_ms") => {
+                    match nv.lit {
+                        syn::Lit::Int(ref linger) => actor_args.max_batch_linger_ms = Some(linger.base10_parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `max_batch_linger_ms = N`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("max_concurrency") => {
+                    match nv.lit {
+                        syn::Lit::Int(ref limit) => actor_args.max_concurrency = Some(limit.base10_parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `max_concurrency = N`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("max_stash_size") => {
+                    match nv.lit {
+                        syn::Lit::Int(ref size) => actor_args.max_stash_size = Some(size.base10_parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `max_stash_size = N`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::List(ref list)) if list.path.is_ident("groups") => {
+                    for group in list.nested.iter() {
+                        match group {
+                            syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.get_ident().is_some() => {
+                                actor_args.groups.push(path.get_ident().unwrap().clone());
+                            }
+                            group => return Err(syn::Error::new(group.span(), "expected `groups(name, ..)`")),
+                        }
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::List(ref list)) if list.path.is_ident("states") => {
+                    for state in list.nested.iter() {
+                        match state {
+                            syn::NestedMeta::Meta(syn::Meta::Path(path)) if path.get_ident().is_some() => {
+                                actor_args.states.push(path.get_ident().unwrap().clone());
+                            }
+                            state => return Err(syn::Error::new(state.span(), "expected `states(Name, ..)`")),
+                        }
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("state") => {
+                    match nv.lit {
+                        syn::Lit::Str(ref name) => actor_args.state = Some(name.parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `state = \"Name\"`")),
+                    }
+                }
+                syn::NestedMeta::Meta(syn::Meta::NameValue(ref nv)) if nv.path.is_ident("group") => {
+                    match nv.lit {
+                        syn::Lit::Str(ref name) => actor_args.group = Some(name.parse()?),
+                        ref lit => return Err(syn::Error::new(lit.span(), "expected `group = \"name\"`")),
+                    }
+                }
+                arg => return Err(syn::Error::new(arg.span(), "unknown derive_actor argument")),
+            }
+        }
+
+        Ok(actor_args)
+    }
+}
+
 #[proc_macro_attribute]
 pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
 {
-    let on_error = args.clone().into_iter().find(|arg| &arg.to_string() == "on_error").is_some();
+    let args = syn::parse_macro_input!(args as syn::AttributeArgs);
+    let args = match ActorArgs::parse(args) {
+        Ok(args) => args,
+        Err(e) => return e.to_compile_error().into(),
+    };
+    let on_error = args.on_error;
     let o_item = item.clone();
     let input: syn::ItemImpl = syn::parse_macro_input!(item as syn::ItemImpl);
-    let o_input: syn::ItemImpl = syn::parse_macro_input!(o_item as syn::ItemImpl);
+    let mut o_input: syn::ItemImpl = syn::parse_macro_input!(o_item as syn::ItemImpl);
+    strip_actor_attrs(&mut o_input);
 
     let attrs = input.attrs;
     let defaultness = input.defaultness;
@@ -37,9 +193,14 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
     let ttrait = input.trait_;
     let self_ty = input.self_ty;
     let brace_token = input.brace_token;
-    let items: Vec<ImplItem> = input.items;
-
-    let mut actor_methods = quote!();
+    let all_items = input.items.clone();
+    // Associated functions are left as they are, only methods become messages
+    let items: Vec<ImplItem> = input.items.into_iter()
+        .filter(|item| match item {
+            ImplItem::Method(method) => has_receiver(&method.sig),
+            _ => true,
+        })
+        .collect();
 
     let type_name = format!("{}", quote!(#self_ty));
 
@@ -49,6 +210,35 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
     let actor_ty = syn::Ident::new(&format!("{}Actor", type_name), self_ty.span());
     let message_ty = syn::Ident::new(&format!("{}Message", type_name), self_ty.span());
     let router_ty = syn::Ident::new(&format!("{}Router", type_name), self_ty.span());
+    let probe_ty = syn::Ident::new(&format!("{}ActorProbe", type_name), self_ty.span());
+    let error_ty = syn::Ident::new(&format!("{}ActorError", type_name), self_ty.span());
+    let context_ty = syn::Ident::new(&format!("{}Context", type_name), self_ty.span());
+    let weak_actor_ty = syn::Ident::new(&format!("Weak{}Actor", type_name), self_ty.span());
+    let join_handle_ty = syn::Ident::new(&format!("{}ActorJoinHandle", type_name), self_ty.span());
+    let exit_reason_ty = syn::Ident::new(&format!("{}ExitReason", type_name), self_ty.span());
+    let envelope_ty = format_ident!("{}Envelope", type_name);
+    let dead_letter_ty = format_ident!("{}DeadLetter", type_name);
+    let dead_letters_ty = format_ident!("{}DeadLetters", type_name);
+    let dead_letter_sink_ty = format_ident!("{}DeadLetterSink", type_name);
+    let default_dead_letters = format_ident!("{}_DEAD_LETTERS", type_name.to_uppercase());
+    let handler_errors_key = format_ident!("{}_HANDLER_ERRORS", type_name.to_uppercase());
+    // Actors declared with `system` share the call chain generated by `actor_system!()`, so cycles
+    // through actors of different types are caught too
+    let (call_chain_key, own_call_chain) = if args.system {
+        (format_ident!("ACTOR_CALL_CHAIN"), quote!())
+    } else {
+        let call_chain_key = format_ident!("{}_CALL_CHAIN", type_name.to_uppercase());
+        let own_call_chain = quote!(
+            // The actors whose handlers are waiting on the one running in this task, followed by it
+            static #call_chain_key: Vec<uuid::Uuid>;
+        );
+        (call_chain_key, own_call_chain)
+    };
+    let error_policy_ty = format_ident!("{}ErrorPolicy", type_name);
+    let stash_ty = format_ident!("{}Stash", type_name);
+
+    let items = mark_context_args(items, &context_ty);
+    let all_items = mark_context_args(all_items, &context_ty);
 
     let method_generics = method_generics(items.clone());
     let method_generic_tys = method_generic_tys(items.clone());
@@ -58,128 +248,243 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
 
 //    let generics_tuple = all_generic_tys_tuple(items.clone(), o_input.clone());
 
-    let message_variants = gen_message_variants(items.clone());
-
-    for item in items.clone() {
-        if let ImplItem::Method(method) = item {
-            if let Visibility::Public(vis) = method.vis {
-                let sig = method.sig.clone();
-                let ident = method.sig.ident;
-
-                let mut args = quote![];
-                let mut arg_and_tys = quote![];
+    // A state's block is a message group that is only accepted in that state
+    if let Some(group) = args.group.as_ref().or(args.state.as_ref()) {
+        return gen_message_group(&args, group, o_input, items, all_items, type_name, &self_ty, &generics)
+            .into_token_stream()
+            .into();
+    }
 
-                for arg in method.sig.inputs {
-                    let arg: FnArg = arg;
-                    match arg {
-                        FnArg::Typed(arg) => {
-                            let arg_name = arg.pat;
-                            let arg_ty = arg.ty;
+    let mut message_variants = gen_message_variants(items.clone()).into_token_stream();
+    let (_, impl_generic_tys, _) = generics.split_for_impl();
+    let mut group_arms = quote!();
+    let mut method_names = gen_method_names(items.clone(), &message_ty).into_token_stream();
+    for group in args.groups.iter().chain(args.states.iter()) {
+        let group_message_ty = format_ident!("{}{}Message", type_name, camel_case(&group.to_string()));
+        message_variants.extend(quote!(#group(#group_message_ty #impl_generic_tys),));
+        group_arms.extend(quote!(
+            #message_ty :: #group(message) => message.dispatch(actor_impl, ctx).await,
+        ));
+        method_names.extend(quote!(
+            #message_ty :: #group(message) => message.method_name(),
+        ));
+    }
+    if !(args.groups.is_empty() && args.states.is_empty()) && merged_generics(items.clone(), o_input.clone()).params.len() != generics.params.len() {
+        return syn::Error::new(
+            self_ty.span(),
+            "an actor with message groups can't have generic message methods",
+        ).to_compile_error().into();
+    }
 
-                            args.extend(quote!(#arg_name ,));
-                            arg_and_tys.extend(quote!(#arg_name : #arg_ty, ));
-                        }
-                        _ => {
-                            continue;
-                        }
-                    }
+    let probe_responders = gen_probe_responders(items.clone(), &message_ty, &all_generic_tys);
+    let args_structs = gen_args_structs(
+        items.clone(),
+        type_name,
+        &message_ty,
+        &merged_generics(items.clone(), o_input.clone()),
+    );
+    let group = if args.broadcast {
+        gen_group(items.clone(), type_name, &actor_ty, &error_ty, &all_generics, &all_generic_tys)
+            .into_token_stream()
+    } else {
+        quote!()
+    };
+    let pool = if args.pool {
+        gen_pool(items.clone(), type_name, &self_ty, &actor_ty, &error_ty, &all_generics, &all_generic_tys)
+            .into_token_stream()
+    } else {
+        quote!()
+    };
+    let log_dead_letter = quote!(
+        tracing::warn!(
+            actor = %dead_letter.actor_name,
+            method = dead_letter.method,
+            correlation_id = %dead_letter.envelope.correlation_id,
+            "dropped an undeliverable message",
+        )
+    );
+    // Actors declared with `system` fall back to the dead letter sink generated by `actor_system!()`
+    let dropped_dead_letter = if args.system {
+        quote!(
+            match ACTOR_DEAD_LETTERS.read().ok().and_then(|sink| sink.clone()) {
+                Some(sink) => sink(ActorDeadLetter {
+                    actor_name: dead_letter.actor_name,
+                    method,
+                    correlation_id: dead_letter.envelope.correlation_id,
+                    sender_id: dead_letter.envelope.sender_id,
+                    message: dead_letter.message,
+                }),
+                None => #log_dead_letter,
+            }
+        )
+    } else {
+        log_dead_letter
+    };
+    let system = if args.system {
+        quote!(
+            #impl_token #all_generics ActorSystemJoinHandle for #join_handle_ty #all_generic_tys
+            where
+                Self: Send + 'static,
+            {
+                fn controls(&self) -> (std::sync::Arc<dyn Fn() + Send + Sync>, std::sync::Arc<dyn Fn() + Send + Sync>) {
+                    let shutdown = self.shutdown.clone();
+                    let kill = self.kill.clone();
+                    (std::sync::Arc::new(move || shutdown.notify()), std::sync::Arc::new(move || kill.abort()))
                 }
+            }
+        )
+    } else {
+        quote!()
+    };
+    let shards = gen_shards(items.clone(), type_name, &actor_ty, &error_ty, &all_generics, &all_generic_tys);
+    let interface = match args.interface {
+        Some(ref interface) => gen_interface(
+            items.clone(),
+            interface,
+            &self_ty,
+            &actor_ty,
+            &error_ty,
+            &context_ty,
+            &all_generics,
+            &all_generic_tys,
+        ).into_token_stream(),
+        None => quote!(),
+    };
 
+    let actor_methods = gen_actor_methods(items.clone(), &message_ty, &actor_ty, &error_ty);
+    let constructors = gen_constructors(all_items, &self_ty, &quote!(#join_handle_ty #all_generic_tys));
 
-                let actor_method = quote!(
-
-                    // #[tracing::instrument(skip(self, #args))]
-                    pub async fn #ident (&self, #arg_and_tys) {
-                        // tracing::trace!("{}.{}", stringify!(#actor_ty), stringify!(#ident));
-
-                        let msg = #message_ty :: #ident { #args };
-
-                        let mut sender = self.sender.clone();
-
-                        let queue_len = self.queue_len.clone();
-
-                        queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-
-                        let task = async move {
-                            if let Err(e) = sender.send(msg)
-                            .await {
-                                panic!(
-                                    concat!(
-                                        "Receiver has failed with {}, propagating error. ",
-                                        stringify!(#actor_ty),
-                                        ".",
-                                        stringify!(#ident)
-                                    ),
-                                    e
-                                )
-                            }
-                        };
-
-                        // let span = tracing::info_span!(
-                        //     concat!(
-                        //         stringify!(#actor_ty),
-                        //         ".",
-                        //         stringify!(#ident)
-                        //     )
-                        // );
-                        let handle = tokio::task::spawn(
-                            // tracing::Instrument::instrument(
-                                task,
-                            //     span
-                            // )
-                        );
-
-                    }
-                );
-
-                actor_methods.extend(actor_method);
-            }
-        }
+    if let Err(e) = check_concurrent(&items).and_then(|_| check_reserved(&items, type_name, &args)) {
+        return e.to_compile_error().into();
     }
 
-    let mut route_arms = quote!();
+    let (route_arms, terminal_arms, terminal_variants, concurrent_arms, concurrent_variants) =
+        gen_route_arms(items.clone(), &message_ty, &self_ty);
+    let route_arms = quote!(#route_arms #group_arms);
 
-    for item in items.clone() {
-        if let ImplItem::Method(method) = item {
-            if let Visibility::Public(ref vis) = method.vis {
-                let ident = method.sig.ident;
+    let is_terminal = if terminal_variants.is_empty() {
+        quote!(false)
+    } else {
+        quote!(matches!(message, #(#terminal_variants)|*))
+    };
 
-                let mut args = quote![];
-                for arg in method.sig.inputs {
-                    let arg: FnArg = arg;
-                    match arg {
-                        FnArg::Typed(arg) => {
-                            let arg_name = arg.pat;
+    // Messages of a state's block are only accepted in that state, the rest go to `unhandled`
+    let state_ty = format_ident!("{}State", type_name);
+    let state_key = format_ident!("{}_STATE", type_name.to_uppercase());
+    let unhandled = items.iter().find_map(|item| match item {
+        ImplItem::Method(method) if method.sig.ident == "unhandled" => Some(method.sig.clone()),
+        _ => None,
+    });
+    let unhandled = match unhandled {
+        Some(sig) if sig.asyncness.is_some() => quote!(actor_impl.unhandled(ctx, message).await),
+        Some(_) => quote!(actor_impl.unhandled(ctx, message)),
+        None => quote!({
+            let envelope = ctx.envelope.clone().unwrap_or_else(|| #envelope_ty::new(None, None));
+            ctx.dead_letters.deliver(envelope, message.method_name(), message);
+        }),
+    };
+    let (states, state_methods, accept_state) = match args.states.first() {
+        None => (quote!(), quote!(), quote!()),
+        Some(initial_state) => {
+            let states = &args.states;
+            let state_variants = quote!(#(#message_ty :: #states (_) => state == #state_ty :: #states,)*);
+
+            (
+                quote!(
+                    /// The states of the actor, which decide the messages it accepts
+                    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+                    pub enum #state_ty {
+                        #(#states,)*
+                    }
 
-                            args.extend(quote!(#arg_name, ));
-                        }
-                        _ => {
-                            continue;
+                    impl Default for #state_ty {
+                        fn default() -> Self {
+                            #state_ty :: #initial_state
                         }
                     }
-                }
 
-                let arm = if method.sig.asyncness.is_some() {
-                    quote!(
-                    #message_ty :: #ident { #args }
-                        => {self. #ident (#args) .await;},
-                    )
-                } else {
-                    quote!(
-                        #message_ty :: #ident { #args } => {
-                            self. #ident (#args);
-                        },
-                    )
-                };
+                    tokio::task_local! {
+                        // The state of the actor running in this task
+                        static #state_key: std::cell::Cell<#state_ty>;
+                    }
+                ),
+                quote!(
+                    /// The state the actor is in
+                    pub fn state(&self) -> #state_ty {
+                        #state_key.try_with(std::cell::Cell::get).unwrap_or_default()
+                    }
 
-                route_arms.extend(arm);
-            }
+                    /// Switches the actor to `state`, from the next message on it only accepts the
+                    /// messages of `state`
+                    pub fn become_state(&self, state: #state_ty) {
+                        let _ = #state_key.try_with(|current| current.set(state));
+                    }
+                ),
+                quote!(
+                    let state = ctx.state();
+                    #[allow(unreachable_patterns)]
+                    let accepted = match message {
+                        #state_variants
+                        _ => true,
+                    };
+                    if !accepted {
+                        #unhandled;
+                        return None;
+                    }
+                ),
+            )
         }
-    }
+    };
+    let scope_state = if args.states.is_empty() {
+        quote!()
+    } else {
+        quote!(let route_f = #state_key.scope(Default::default(), route_f);)
+    };
+
+    // An ActorImpl that defines `intercept` sees every message before it's handled, and can take it,
+    // e.g. to stash it
+    let intercept = items.iter().find_map(|item| match item {
+        ImplItem::Method(method) if method.sig.ident == "intercept" => Some(method.sig.clone()),
+        _ => None,
+    });
+    let intercept = match intercept {
+        Some(sig) if sig.asyncness.is_some() => quote!(
+            let message = actor_impl.intercept(ctx, message).await?;
+        ),
+        Some(_) => quote!(
+            let message = actor_impl.intercept(ctx, message)?;
+        ),
+        None => quote!(),
+    };
+    // While the hooks run, messages they stash keep the envelope of the message they were given
+    let has_intercept = !intercept.is_empty();
+    let screen = |hooks, has_hooks: bool| if !has_hooks {
+        quote!()
+    } else {
+        quote!(
+            ctx.intercepting = true;
+            let message = async { #hooks Some(message) }.await;
+            ctx.intercepting = false;
+            let message = match message {
+                Some(message) => message,
+                None => return,
+            };
+        )
+    };
+    let screen_concurrent = screen(intercept.clone(), has_intercept);
+    let screen = screen(quote!(#intercept #accept_state), has_intercept || !args.states.is_empty());
+
+    let max_stash_size = args.max_stash_size.unwrap_or(128);
+    let shared_stash_ty = quote!(std::sync::Arc<std::sync::Mutex<#stash_ty #all_generic_tys>>);
 
-    let route_msg = if on_error {
+    let dispatch = if on_error {
         quote!{
-            async fn route_message(&mut self, message: #message_ty #all_generic_tys ) {
+            async fn dispatch(
+                actor_impl: &mut #self_ty,
+                ctx: &mut #context_ty #all_generic_tys,
+                message: #message_ty #all_generic_tys,
+            ) {
+                #screen
                 let route_f = async {
                     match message {
                         #route_arms
@@ -195,7 +500,12 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
         }
     } else {
         quote!{
-            async fn route_message(&mut self, message: #message_ty #all_generic_tys ) {
+            async fn dispatch(
+                actor_impl: &mut #self_ty,
+                ctx: &mut #context_ty #all_generic_tys,
+                message: #message_ty #all_generic_tys,
+            ) {
+                #screen
                 match message {
                     #route_arms
                 };
@@ -203,6 +513,209 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
         }
     };
 
+    // Only actors with concurrent handlers need their ActorImpl to be `Sync`
+    let max_concurrency = args.max_concurrency.unwrap_or(16);
+    let (route_concurrent, concurrent) = if concurrent_variants.is_empty() {
+        (quote!(), quote!())
+    } else {
+        let dispatch_concurrent = if on_error {
+            quote!(
+                let route_f = std::panic::AssertUnwindSafe(route_f);
+                let route_f = futures::FutureExt::catch_unwind(route_f);
+                let _ = tracing::Instrument::instrument(#call_chain_key.scope(call_chain, route_f), span).await;
+            )
+        } else {
+            quote!(
+                tracing::Instrument::instrument(#call_chain_key.scope(call_chain, route_f), span).await;
+            )
+        };
+
+        let route_concurrent = quote!(
+            // Concurrent messages run until the next exclusive message, which is routed here
+            if Self::is_concurrent(&msg) {
+                match self.route_concurrent(envelope, msg).await {
+                    Some(next) => {
+                        envelope = next.0;
+                        msg = next.1;
+                    }
+                    None => return,
+                }
+            }
+        );
+        let concurrent = quote!(
+            fn is_concurrent(message: &#message_ty #all_generic_tys) -> bool {
+                matches!(message, #(#concurrent_variants)|*)
+            }
+
+            async fn dispatch_concurrent(
+                actor_uuid: uuid::Uuid,
+                actor_impl: &#self_ty,
+                ctx: &#context_ty #all_generic_tys,
+                mut envelope: #envelope_ty,
+                message: #message_ty #all_generic_tys,
+            ) {
+                let call_chain = Self::call_chain(actor_uuid, &mut envelope);
+                let span = Self::span(&envelope);
+                let ctx = &mut ctx.for_envelope(envelope);
+                #screen_concurrent
+                let ctx = &*ctx;
+
+                let route_f = async {
+                    match message {
+                        #concurrent_arms
+                        _ => unreachable!(),
+                    };
+                };
+                #dispatch_concurrent
+            }
+
+            // Runs `msg` alongside the concurrent messages behind it, only sharing the ActorImpl.
+            // The first exclusive message waits for all of them to finish, then is handed back
+            async fn route_concurrent(
+                &mut self,
+                envelope: #envelope_ty,
+                msg: #message_ty #all_generic_tys,
+            ) -> Option<(#envelope_ty, #message_ty #all_generic_tys)> {
+                let actor_uuid = self.actor_uuid;
+                let actor_impl = self.actor_impl.as_ref().expect("route_concurrent actor_impl was None");
+                let ctx = &self.ctx;
+                let receiver = &mut self.receiver;
+                let queue_len = &self.queue_len;
+
+                let mut running = futures::stream::FuturesUnordered::new();
+                if !Self::is_dropped(&envelope) {
+                    running.push(Self::dispatch_concurrent(actor_uuid, actor_impl, ctx, envelope, msg));
+                }
+
+                let mut receiving = true;
+                let mut exclusive = None;
+                while !running.is_empty() {
+                    if !receiving || running.len() >= #max_concurrency {
+                        futures::StreamExt::next(&mut running).await;
+                        continue;
+                    }
+
+                    // Unstashed messages go before the rest of the mailbox
+                    let unstashed = ctx.stash.lock().unwrap_or_else(|e| e.into_inner()).unstashed.pop_front();
+                    let received = match unstashed {
+                        Some(msg) => Some(msg),
+                        None => match futures::future::select(
+                            futures::StreamExt::next(&mut running),
+                            Box::pin(receiver.recv()),
+                        ).await {
+                            futures::future::Either::Left(_) => continue,
+                            futures::future::Either::Right((msg, _)) => msg,
+                        },
+                    };
+
+                    match received {
+                        Some((envelope, msg)) => {
+                            queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                            if Self::is_dropped(&envelope) {
+                                continue;
+                            } else if Self::is_concurrent(&msg) {
+                                running.push(Self::dispatch_concurrent(actor_uuid, actor_impl, ctx, envelope, msg));
+                            } else {
+                                exclusive = Some((envelope, msg));
+                                receiving = false;
+                            }
+                        }
+                        None => receiving = false,
+                    }
+                }
+
+                exclusive
+            }
+        );
+
+        (route_concurrent, concurrent)
+    };
+
+    // An ActorImpl that defines `handle_batch` gets everything queued in its mailbox at once
+    let handle_batch = items.iter().find_map(|item| match item {
+        ImplItem::Method(method) if method.sig.ident == "handle_batch" => Some(method.sig.clone()),
+        _ => None,
+    });
+
+    let max_batch_size = args.max_batch_size.unwrap_or(128);
+    let max_batch_linger_ms = args.max_batch_linger_ms.unwrap_or(0);
+
+    let (mailbox_capacity, route_next, route_batch) = match handle_batch {
+        Some(sig) => {
+            let handle = if sig.asyncness.is_some() {
+                quote!(actor_impl.handle_batch(batch).await)
+            } else {
+                quote!(actor_impl.handle_batch(batch))
+            };
+
+            let route_batch = quote!(
+                async fn route_batch(&mut self, msg: (#envelope_ty, #message_ty #all_generic_tys)) {
+                    let linger = std::time::Duration::from_millis(#max_batch_linger_ms);
+                    let linger_until = tokio::time::Instant::now() + linger;
+
+                    // Terminal messages can't be batched, they're routed after the batch instead
+                    if Self::is_terminal(&msg.1) {
+                        return self.route(msg).await;
+                    }
+
+                    let mut batch = vec![msg];
+                    let mut terminal = None;
+                    while batch.len() < #max_batch_size {
+                        // Unstashed messages go before the rest of the mailbox
+                        let msg = match self.next_unstashed() {
+                            Some(msg) => msg,
+                            None => match self.receiver.try_recv() {
+                                Ok(msg) => msg,
+                                Err(_) if linger.as_millis() == 0 => break,
+                                Err(_) => match tokio::time::timeout_at(linger_until, self.receiver.recv()).await {
+                                    Ok(Some(msg)) => msg,
+                                    _ => break,
+                                },
+                            },
+                        };
+
+                        if Self::is_terminal(&msg.1) {
+                            terminal = Some(msg);
+                            break;
+                        }
+                        batch.push(msg);
+                    }
+
+                    self.queue_len.fetch_sub(batch.len(), std::sync::atomic::Ordering::SeqCst);
+                    let batch: Vec<(#envelope_ty, #message_ty #all_generic_tys)> = batch.into_iter()
+                        .filter(|(envelope, _)| !Self::is_dropped(envelope))
+                        .collect();
+
+                    if !batch.is_empty() {
+                        let actor_impl = self.actor_impl.as_mut().expect("route_batch actor_impl was None");
+                        #handle;
+                    }
+
+                    if let Some(msg) = terminal {
+                        self.route(msg).await;
+                    }
+                }
+            );
+
+            (max_batch_size, quote!(self.route_batch(msg).await), route_batch)
+        }
+        None => (1, quote!(self.route(msg).await), quote!()),
+    };
+
+    // An ActorImpl that defines `on_handler_error` picks what happens after a handler's error
+    let on_handler_error = items.iter().find_map(|item| match item {
+        ImplItem::Method(method) if method.sig.ident == "on_handler_error" => Some(method.sig.clone()),
+        _ => None,
+    });
+    let on_handler_error = match on_handler_error {
+        Some(sig) if sig.asyncness.is_some() => quote!(actor_impl.on_handler_error(method, &*error).await),
+        Some(_) => quote!(actor_impl.on_handler_error(method, &*error)),
+        None => quote!({
+            tracing::warn!(actor = stringify!(#actor_ty), method, "a fire-and-forget handler returned an error");
+            #error_policy_ty::Resume
+        }),
+    };
+
     let result = quote! {
         #o_input
         // Message
@@ -212,105 +725,742 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
             #message_variants
         }
 
-        // Actor route_msg impl
-        #[async_trait]
-        impl #all_generics aktors::actor::Actor < #message_ty #all_generic_tys > for #self_ty
-        {
-            // #[instrument(skip(self, message))]
-            #route_msg
-
-            fn get_actor_name(&self) -> &str {
-                &self.self_actor.as_ref().unwrap().actor_name
+        #impl_token #all_generics #message_ty #all_generic_tys {
+            /// The name of the method this message is handled by
+            pub fn method_name(&self) -> &'static str {
+                match self {
+                    #method_names
+                }
             }
+        }
 
-            fn close(&mut self) {
-                self.self_actor = None;
-            }
+        tokio::task_local! {
+            #own_call_chain
+            // The errors of fire-and-forget handlers, waiting on the router's error policy
+            static #handler_errors_key: std::cell::RefCell<Vec<(&'static str, Box<dyn std::any::Any + Send>)>>;
         }
 
-        // Actor Struct
-        pub struct #actor_ty #all_generics {
-            sender: Sender<#message_ty #all_generic_tys>,
-            inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
-            queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
-            actor_name: String,
-            actor_uuid: uuid::Uuid,
-            actor_num: usize,
+        #states
+
+        // Messages put aside by handlers, and those unstashed to be routed before the mailbox
+        struct #stash_ty #all_generics {
+            stashed: std::collections::VecDeque<(#envelope_ty, #message_ty #all_generic_tys)>,
+            unstashed: std::collections::VecDeque<(#envelope_ty, #message_ty #all_generic_tys)>,
         }
 
-        // Actor Impl block
-        #impl_token #all_generics #actor_ty #all_generic_tys {
-            pub async fn new (mut actor_impl: #self_ty) -> (Self, tokio::task::JoinHandle<()>) {
-                let (sender, receiver) = channel(1);
-                let inner_rc = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(1));
-                let queue_len = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        #impl_token #all_generics Default for #stash_ty #all_generic_tys {
+            fn default() -> Self {
+                Self {
+                    stashed: std::collections::VecDeque::new(),
+                    unstashed: std::collections::VecDeque::new(),
+                }
+            }
+        }
 
-                let actor_uuid = uuid::Uuid::new_v4();
-                let actor_name = format!(
-                    "{} {} {}",
-                     stringify!(#actor_ty),
-                     actor_uuid,
-                     0,
-                );
-                let inner_actor = Self {
-                  sender,
-                  inner_rc: inner_rc.clone(),
-                  queue_len: queue_len.clone(),
-                  actor_name,
-                  actor_uuid,
-                  actor_num: 0,
-                };
+        /// What an actor does after a handler returns an `Err` that nobody is waiting on
+        pub enum #error_policy_ty #generics {
+            /// Keep handling messages with the same ActorImpl
+            Resume,
+            /// Close the ActorImpl, then handle the next messages with this one
+            Restart(#self_ty),
+            /// Stop the actor, which exits with `Failed` and the error
+            Stop,
+        }
 
-                let self_actor = inner_actor.clone();
+        /// Where a message came from and how long it's worth handling, handlers can read the
+        /// envelope of their message through the context
+        #[derive(Debug, Clone)]
+        pub struct #envelope_ty {
+            /// Identifies the message, and any reply to it, in traces
+            pub correlation_id: uuid::Uuid,
+            /// The actor whose handler sent the message, if it was sent by one of the same type, or
+            /// by any `system` actor to a `system` actor
+            pub sender_id: Option<uuid::Uuid>,
+            /// When the message was sent
+            pub enqueued_at: std::time::Instant,
+            /// When the message stops being worth handling, expired messages are dropped
+            pub deadline: Option<std::time::Instant>,
+            // The actors whose handlers are waiting on a request, only tracked in debug builds
+            call_chain: Vec<uuid::Uuid>,
+            // Dropped along with the caller waiting on the message
+            cancellation: Option<std::sync::Weak<()>>,
+        }
 
-                actor_impl.self_actor = Some(inner_actor);
+        impl #envelope_ty {
+            fn new(sender_id: Option<uuid::Uuid>, deadline: Option<std::time::Instant>) -> Self {
+                Self {
+                    correlation_id: uuid::Uuid::new_v4(),
+                    sender_id,
+                    enqueued_at: std::time::Instant::now(),
+                    deadline,
+                    call_chain: Vec::new(),
+                    cancellation: None,
+                }
+            }
 
-                // let span = tracing::info_span!(
-                //     concat!(
-                //         stringify!(#actor_ty),
-                //     )
-                // );
-                let task = aktors::actor::route_wrapper(
-                    aktors::actor::Router::new(
-                        actor_impl,
-                        receiver,
-                        inner_rc,
-                        queue_len
-                    )
-                );
+            // The actor whose handler is running in this task
+            fn current_sender() -> Option<uuid::Uuid> {
+                #call_chain_key.try_with(|call_chain| call_chain.last().copied()).ok().flatten()
+            }
 
-                let handle = tokio::task::spawn(
-                    // tracing::Instrument::instrument(
-                        task,
-                        // span
-                    // )
-                );
+            // An envelope for another message from the same sender
+            fn for_next_message(&self) -> Self {
+                Self::new(self.sender_id, self.deadline)
+            }
 
-                (self_actor, handle)
+            fn with_call_chain(mut self) -> Self {
+                if cfg!(debug_assertions) {
+                    self.call_chain = #call_chain_key.try_with(Clone::clone).unwrap_or_default();
+                }
+                self
             }
 
-            #actor_methods
+            /// How long ago the message was sent
+            pub fn age(&self) -> std::time::Duration {
+                self.enqueued_at.elapsed()
+            }
 
-        }
+            /// Whether the message's deadline has passed
+            pub fn is_expired(&self) -> bool {
+                self.deadline.map_or(false, |deadline| std::time::Instant::now() >= deadline)
+            }
 
-        impl #all_generics std::fmt::Debug for #actor_ty #all_generic_tys
-        {
-            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                f.debug_struct(stringify!(#actor_ty))
-                 .field("actor_name", &self.actor_name)
-                 .finish()
+            /// Whether the caller waiting on the message has gone away
+            pub fn is_cancelled(&self) -> bool {
+                self.cancellation.as_ref().map_or(false, |token| token.strong_count() == 0)
             }
         }
 
-        impl #all_generics std::clone::Clone for #actor_ty #all_generic_tys
-        {
-            fn clone(&self) -> Self {
-                self.inner_rc.clone().fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        /// A message that couldn't be delivered, because the actor had stopped or didn't accept it
+        /// in its state
+        pub struct #dead_letter_ty {
+            /// The actor the message was sent to
+            pub actor_name: String,
+            /// The method the message was sent through
+            pub method: &'static str,
+            pub envelope: #envelope_ty,
+            /// The undelivered message, which `downcast`s to its message type
+            pub message: Box<dyn std::any::Any + Send>,
+        }
+
+        type #dead_letter_sink_ty = std::sync::Arc<dyn Fn(#dead_letter_ty) + Send + Sync>;
+
+        // Used for actors of this type that don't have a sink of their own, before the crate-wide one
+        static #default_dead_letters: std::sync::RwLock<Option<#dead_letter_sink_ty>> = std::sync::RwLock::new(None);
 
+        // Where an actor's undeliverable messages go, shared by all of its handles
+        #[derive(Clone)]
+        struct #dead_letters_ty {
+            actor_name: std::sync::Arc<str>,
+            sink: std::sync::Arc<std::sync::RwLock<Option<#dead_letter_sink_ty>>>,
+        }
+
+        impl #dead_letters_ty {
+            fn new(actor_uuid: uuid::Uuid) -> Self {
                 Self {
-                    sender: self.sender.clone(),
-                    inner_rc: self.inner_rc.clone(),
+                    actor_name: format!("{} {}", stringify!(#actor_ty), actor_uuid).into(),
+                    sink: Default::default(),
+                }
+            }
+
+            // Hands the message to this actor's sink, or its type's, or for a `system` actor the
+            // `ActorSystem`'s, or else logs it
+            fn deliver(&self, envelope: #envelope_ty, method: &'static str, message: impl std::any::Any + Send) {
+                let sink = self.sink.read().ok().and_then(|sink| sink.clone())
+                    .or_else(|| #default_dead_letters.read().ok().and_then(|sink| sink.clone()));
+
+                let dead_letter = #dead_letter_ty {
+                    actor_name: self.actor_name.to_string(),
+                    method,
+                    envelope,
+                    message: Box::new(message),
+                };
+                if let Some(sink) = sink {
+                    return sink(dead_letter);
+                }
+
+                #dropped_dead_letter
+            }
+        }
+
+        #args_structs
+
+        // Actor route_msg impl
+        #[async_trait]
+        impl #all_generics aktors::actor::Actor < #message_ty #all_generic_tys > for #self_ty
+        {
+            // #[instrument(skip(self, message))]
+            async fn route_message(&mut self, message: #message_ty #all_generic_tys ) {
+                // `self_actor` may be either a strong or a weak handle, both have these fields
+                let mut ctx = match self.self_actor.as_ref() {
+                    Some(self_actor) => #context_ty::new(
+                        &self_actor.sender,
+                        &self_actor.queue_len,
+                        &self_actor.stopped,
+                        &self_actor.dead_letters,
+                        &self_actor.stash,
+                    ),
+                    None => #context_ty::detached(),
+                };
+                #router_ty::dispatch(self, &mut ctx, message).await;
+            }
+
+            fn get_actor_name(&self) -> &str {
+                &self.self_actor.as_ref().unwrap().actor_name
+            }
+
+            fn close(&mut self) {
+                self.self_actor = None;
+            }
+        }
+
+        // Routes a message along with its envelope, e.g. one of a batch handed to `handle_batch`
+        #[async_trait]
+        impl #all_generics aktors::actor::Actor < (#envelope_ty, #message_ty #all_generic_tys) > for #self_ty
+        {
+            async fn route_message(&mut self, message: (#envelope_ty, #message_ty #all_generic_tys)) {
+                let (mut envelope, message) = message;
+                let (mut ctx, actor_uuid) = match self.self_actor.as_ref() {
+                    Some(self_actor) => (
+                        #context_ty::new(
+                            &self_actor.sender,
+                            &self_actor.queue_len,
+                            &self_actor.stopped,
+                            &self_actor.dead_letters,
+                            &self_actor.stash,
+                        ),
+                        self_actor.actor_uuid,
+                    ),
+                    None => (#context_ty::detached(), uuid::Uuid::nil()),
+                };
+
+                let call_chain = <#router_ty #all_generic_tys>::call_chain(actor_uuid, &mut envelope);
+                let span = <#router_ty #all_generic_tys>::span(&envelope);
+                ctx.envelope = Some(envelope);
+                let route_f = #call_chain_key.scope(call_chain, <#router_ty #all_generic_tys>::dispatch(self, &mut ctx, message));
+                tracing::Instrument::instrument(route_f, span).await;
+            }
+
+            fn get_actor_name(&self) -> &str {
+                <#self_ty as aktors::actor::Actor<#message_ty #all_generic_tys>>::get_actor_name(self)
+            }
+
+            fn close(&mut self) {
+                <#self_ty as aktors::actor::Actor<#message_ty #all_generic_tys>>::close(self)
+            }
+        }
+
+        // Router
+        struct #router_ty #all_generics {
+            actor_impl: Option<#self_ty>,
+            receiver: tokio::sync::mpsc::Receiver<(#envelope_ty, #message_ty #all_generic_tys)>,
+            inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+            ctx: #context_ty #all_generic_tys,
+            actor_uuid: uuid::Uuid,
+            dead_letters: #dead_letters_ty,
+            // Notified by the join handle to stop taking messages
+            shutdown: std::sync::Arc<tokio::sync::Notify>,
+            // The handler error that the error policy stopped the actor for
+            failure: Option<Box<dyn std::any::Any + Send>>,
+            // The strong handles held by the ActorImpl itself, 1 for `self_actor: Option<XActor>`
+            // and 0 for `self_actor: Option<WeakXActor>`
+            self_refs: usize,
+        }
+
+        #impl_token #all_generics #router_ty #all_generic_tys {
+            async fn route_wrapper(mut self) -> (#exit_reason_ty, Option<#self_ty>) {
+                // A panicking handler stops the actor, but the ActorImpl is still handed back
+                let route_f = #call_chain_key.scope(vec![self.actor_uuid], self.route_loop());
+                let route_f = #handler_errors_key.scope(Default::default(), route_f);
+                #scope_state
+                let route_f = std::panic::AssertUnwindSafe(route_f);
+                let result = futures::FutureExt::catch_unwind(route_f).await;
+
+                let mut actor_impl = self.actor_impl.take();
+                if let Some(actor_impl) = actor_impl.as_mut() {
+                    <#self_ty as aktors::actor::Actor<#message_ty #all_generic_tys>>::close(actor_impl);
+                }
+                self.receiver.close();
+                self.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+
+                // Whatever is left in the mailbox will never be handled
+                while let Ok((envelope, msg)) = self.receiver.try_recv() {
+                    self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    self.dead_letters.deliver(envelope, msg.method_name(), msg);
+                }
+
+                // Nor will the stashed messages
+                let stash = std::mem::take(&mut *self.ctx.stash.lock().unwrap_or_else(|e| e.into_inner()));
+                self.queue_len.fetch_sub(stash.unstashed.len(), std::sync::atomic::Ordering::SeqCst);
+                for (envelope, msg) in stash.unstashed.into_iter().chain(stash.stashed) {
+                    self.dead_letters.deliver(envelope, msg.method_name(), msg);
+                }
+
+                match (result, self.failure.take()) {
+                    (Ok(()), None) => (#exit_reason_ty::Normal, actor_impl),
+                    (Ok(()), Some(error)) => (#exit_reason_ty::Failed(error), actor_impl),
+                    (Err(payload), _) => (#exit_reason_ty::Panicked(payload), actor_impl),
+                }
+            }
+
+            fn next_unstashed(&self) -> Option<(#envelope_ty, #message_ty #all_generic_tys)> {
+                self.ctx.stash.lock().unwrap_or_else(|e| e.into_inner()).unstashed.pop_front()
+            }
+
+            // Applies the error policy to the errors of the handlers that just ran, false if
+            // the actor should stop
+            async fn handle_errors(&mut self) -> bool {
+                let errors = #handler_errors_key
+                    .try_with(|errors| std::mem::take(&mut *errors.borrow_mut()))
+                    .unwrap_or_default();
+
+                for (method, error) in errors {
+                    let policy = match self.actor_impl.as_mut() {
+                        Some(actor_impl) => #on_handler_error,
+                        None => #error_policy_ty::Resume,
+                    };
+
+                    match policy {
+                        #error_policy_ty::Resume => {}
+                        #error_policy_ty::Restart(mut actor_impl) => {
+                            let mut failed_impl = self.actor_impl.take().expect("handle_errors actor_impl was None");
+                            actor_impl.self_actor = failed_impl.self_actor.take();
+                            <#self_ty as aktors::actor::Actor<#message_ty #all_generic_tys>>::close(&mut failed_impl);
+                            self.actor_impl = Some(actor_impl);
+                        }
+                        #error_policy_ty::Stop => {
+                            self.failure = Some(error);
+                            return false;
+                        }
+                    }
+                }
+
+                true
+            }
+
+            async fn route_loop(&mut self) {
+                let mut empty_tries = 0;
+
+                loop {
+                    let msg = match self.next_unstashed() {
+                        // Unstashed messages go before the rest of the mailbox
+                        Some(msg) => Ok(Some(Some(msg))),
+                        None => {
+                            let next = futures::future::select(
+                                Box::pin(self.receiver.recv()),
+                                Box::pin(self.shutdown.notified()),
+                            );
+                            // `None` once the actor is asked to shut down
+                            let next = futures::FutureExt::map(next, |next| match next {
+                                futures::future::Either::Left((msg, _)) => Some(msg),
+                                futures::future::Either::Right(_) => None,
+                            });
+                            tokio::time::timeout(
+                                std::time::Duration::from_millis(empty_tries + 210),
+                                next,
+                            ).await
+                        }
+                    };
+
+                    match msg {
+                        Ok(Some(Some(msg))) => {
+                            empty_tries = 0;
+                            #route_next;
+                            if !self.handle_errors().await {
+                                break;
+                            }
+                        }
+                        // Stop taking messages, the ones already queued are still handled
+                        Ok(None) => {
+                            self.receiver.close();
+                            self.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+                            continue;
+                        }
+                        // Queue was empty for timeout duration
+                        Err(_) => {
+                            if empty_tries > 90 {
+                                empty_tries = 0;
+                            }
+                            empty_tries += 1;
+                        }
+                        // Disconnected, or shut down and drained
+                        Ok(Some(None)) => {
+                            if self.stopped.load(std::sync::atomic::Ordering::SeqCst) {
+                                break;
+                            }
+                            empty_tries = 0;
+                        }
+                    }
+
+                    // A terminal message consumed the ActorImpl
+                    if self.actor_impl.is_none() {
+                        break;
+                    }
+
+                    let inner_rc = self.inner_rc.load(std::sync::atomic::Ordering::SeqCst);
+                    let queue_len = self.queue_len.load(std::sync::atomic::Ordering::SeqCst);
+
+                    if queue_len > 0 {
+                        continue
+                    }
+
+                    if inner_rc <= self.self_refs {
+                        break;
+                    }
+                }
+            }
+
+            async fn route(&mut self, (mut envelope, mut msg): (#envelope_ty, #message_ty #all_generic_tys)) {
+                self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+
+                #route_concurrent
+
+                if Self::is_dropped(&envelope) {
+                    return;
+                }
+
+                let call_chain = Self::call_chain(self.actor_uuid, &mut envelope);
+                let span = Self::span(&envelope);
+                self.ctx.envelope = Some(envelope);
+
+                // A terminal message stops the actor, then takes ownership of the ActorImpl
+                if Self::is_terminal(&msg) {
+                    let mut actor_impl = self.actor_impl.take().expect("route_message actor_impl was None");
+                    <#self_ty as aktors::actor::Actor<#message_ty #all_generic_tys>>::close(&mut actor_impl);
+                    self.receiver.close();
+                    self.stopped.store(true, std::sync::atomic::Ordering::SeqCst);
+
+                    let route_f = #call_chain_key.scope(call_chain, Self::dispatch_terminal(actor_impl, &mut self.ctx, msg));
+                    tracing::Instrument::instrument(route_f, span).await;
+                    return;
+                }
+
+                let actor_impl = self.actor_impl.as_mut().expect("route_message actor_impl was None");
+                let route_f = #call_chain_key.scope(call_chain, Self::dispatch(actor_impl, &mut self.ctx, msg));
+                tracing::Instrument::instrument(route_f, span).await;
+            }
+
+            // Nobody is waiting on an expired or cancelled message anymore, so it's dropped unhandled
+            fn is_dropped(envelope: &#envelope_ty) -> bool {
+                let dropped = envelope.is_expired() || envelope.is_cancelled();
+                if dropped {
+                    tracing::trace!(
+                        actor = stringify!(#actor_ty),
+                        correlation_id = %envelope.correlation_id,
+                        "dropped an expired or cancelled message",
+                    );
+                }
+                dropped
+            }
+
+            // The actors waiting on a request, followed by this one
+            fn call_chain(actor_uuid: uuid::Uuid, envelope: &mut #envelope_ty) -> Vec<uuid::Uuid> {
+                let mut call_chain = std::mem::take(&mut envelope.call_chain);
+                call_chain.push(actor_uuid);
+                call_chain
+            }
+
+            fn span(envelope: &#envelope_ty) -> tracing::Span {
+                tracing::trace_span!(
+                    "message",
+                    actor = stringify!(#actor_ty),
+                    correlation_id = %envelope.correlation_id,
+                    sender_id = ?envelope.sender_id,
+                    queued_us = envelope.age().as_micros() as u64,
+                )
+            }
+
+            #dispatch
+
+            #concurrent
+
+            fn is_terminal(message: &#message_ty #all_generic_tys) -> bool {
+                #is_terminal
+            }
+
+            async fn dispatch_terminal(
+                actor_impl: #self_ty,
+                ctx: &mut #context_ty #all_generic_tys,
+                message: #message_ty #all_generic_tys,
+            ) {
+                match message {
+                    #terminal_arms
+                    _ => unreachable!(),
+                }
+            }
+
+            #route_batch
+        }
+
+        // Actor Struct
+        pub struct #actor_ty #all_generics {
+            sender: Sender<(#envelope_ty, #message_ty #all_generic_tys)>,
+            inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+            actor_name: String,
+            actor_uuid: uuid::Uuid,
+            actor_num: usize,
+            deadline: Option<std::time::Instant>,
+            dead_letters: #dead_letters_ty,
+            stash: #shared_stash_ty,
+        }
+
+        // Actor Impl block
+        #impl_token #all_generics #actor_ty #all_generic_tys {
+            pub async fn new (mut actor_impl: #self_ty) -> (Self, #join_handle_ty #all_generic_tys) {
+                let (sender, receiver) = channel(#mailbox_capacity);
+                let inner_rc = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(1));
+                let queue_len = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+                let stopped = std::sync::Arc::new(std::sync::atomic::AtomicBool::new(false));
+
+                let actor_uuid = uuid::Uuid::new_v4();
+                let actor_name = format!(
+                    "{} {} {}",
+                     stringify!(#actor_ty),
+                     actor_uuid,
+                     0,
+                );
+                let inner_actor = Self {
+                  sender,
+                  inner_rc: inner_rc.clone(),
+                  queue_len: queue_len.clone(),
+                  stopped: stopped.clone(),
+                  actor_name,
+                  actor_uuid,
+                  actor_num: 0,
+                  deadline: None,
+                  dead_letters: #dead_letters_ty::new(actor_uuid),
+                  stash: Default::default(),
+                };
+
+                let self_actor = inner_actor.clone();
+                let dead_letters = inner_actor.dead_letters.clone();
+                let shutdown = std::sync::Arc::new(tokio::sync::Notify::new());
+                let ctx = #context_ty::new(
+                    &inner_actor.sender,
+                    &inner_actor.queue_len,
+                    &inner_actor.stopped,
+                    &inner_actor.dead_letters,
+                    &inner_actor.stash,
+                );
+
+                actor_impl.self_actor = Some(inner_actor.into());
+
+                // Everything but the handle returned to the caller belongs to the ActorImpl
+                let self_refs = inner_rc.load(std::sync::atomic::Ordering::SeqCst) - 1;
+
+                // let span = tracing::info_span!(
+                //     concat!(
+                //         stringify!(#actor_ty),
+                //     )
+                // );
+                let task = #router_ty {
+                    actor_impl: Some(actor_impl),
+                    receiver,
+                    inner_rc,
+                    queue_len,
+                    stopped,
+                    ctx,
+                    actor_uuid,
+                    dead_letters,
+                    shutdown: shutdown.clone(),
+                    failure: None,
+                    self_refs,
+                }.route_wrapper();
+
+                let (kill, registration) = futures::future::AbortHandle::new_pair();
+                let handle = tokio::task::spawn(
+                    // tracing::Instrument::instrument(
+                        futures::future::Abortable::new(task, registration),
+                        // span
+                    // )
+                );
+
+                (self_actor, #join_handle_ty { handle, shutdown, kill })
+            }
+
+            /// Creates an actor handle that is backed by a probe instead of an `ActorImpl`.
+            /// Every message sent through the handle is recorded by the probe, which lets
+            /// tests assert on calls and script the replies of request/response methods.
+            pub fn probe() -> (Self, #probe_ty #all_generic_tys) {
+                let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+                let (recorder, recorded) = tokio::sync::mpsc::unbounded_channel();
+                let inner_rc = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(1));
+                let queue_len = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+
+                let actor_uuid = uuid::Uuid::new_v4();
+                let actor_name = format!(
+                    "{} {} {}",
+                     stringify!(#probe_ty),
+                     actor_uuid,
+                     0,
+                );
+                let probe_actor = Self {
+                  sender,
+                  inner_rc,
+                  queue_len: queue_len.clone(),
+                  stopped: Default::default(),
+                  actor_name,
+                  actor_uuid,
+                  actor_num: 0,
+                  deadline: None,
+                  dead_letters: #dead_letters_ty::new(actor_uuid),
+                  stash: Default::default(),
+                };
+
+                let probe = #probe_ty {
+                    recorded,
+                    responders: Default::default(),
+                };
+                let responders = probe.responders.clone();
+
+                tokio::task::spawn(async move {
+                    while let Some((_, mut msg)) = receiver.recv().await {
+                        queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+
+                        for respond in responders.lock().unwrap().iter_mut() {
+                            if respond(&mut msg) {
+                                break;
+                            }
+                        }
+
+                        // The probe itself may already be gone, that's fine
+                        let _ = recorder.send(msg);
+                    }
+                });
+
+                (probe_actor, probe)
+            }
+
+            /// Whether the actor has finished handling messages and shut down
+            pub fn is_stopped(&self) -> bool {
+                self.stopped.load(std::sync::atomic::Ordering::SeqCst)
+            }
+
+            /// A handle whose messages are dropped unhandled if the actor hasn't started on them
+            /// by `deadline`
+            pub fn with_deadline(&self, deadline: std::time::Instant) -> Self {
+                let mut actor = self.clone();
+                actor.deadline = Some(deadline);
+                actor
+            }
+
+            fn envelope(&self) -> #envelope_ty {
+                #envelope_ty::new(#envelope_ty::current_sender(), self.deadline)
+            }
+
+            /// Sends messages this actor can no longer deliver to `sink`, instead of the default
+            pub fn set_dead_letters(&self, sink: impl Fn(#dead_letter_ty) + Send + Sync + 'static) {
+                if let Ok(mut dead_letters) = self.dead_letters.sink.write() {
+                    *dead_letters = Some(std::sync::Arc::new(sink));
+                }
+            }
+
+            /// Sends messages that actors of this type can no longer deliver to `sink`, instead of
+            /// the crate-wide one
+            pub fn set_default_dead_letters(sink: impl Fn(#dead_letter_ty) + Send + Sync + 'static) {
+                if let Ok(mut dead_letters) = #default_dead_letters.write() {
+                    *dead_letters = Some(std::sync::Arc::new(sink));
+                }
+            }
+
+            /// Creates a handle that doesn't keep the actor alive
+            pub fn downgrade(&self) -> #weak_actor_ty #all_generic_tys {
+                #weak_actor_ty {
+                    sender: self.sender.clone(),
+                    inner_rc: self.inner_rc.clone(),
+                    queue_len: self.queue_len.clone(),
+                    stopped: self.stopped.clone(),
+                    actor_name: self.actor_name.clone(),
+                    actor_uuid: self.actor_uuid,
+                    actor_num: self.actor_num,
+                    dead_letters: self.dead_letters.clone(),
+                    stash: self.stash.clone(),
+                }
+            }
+
+            /// The number of messages sent to the actor that it hasn't started handling yet
+            pub fn queue_len(&self) -> usize {
+                self.queue_len.load(std::sync::atomic::Ordering::SeqCst)
+            }
+
+            /// Sends an already constructed message to the actor, waiting for room in its mailbox
+            pub async fn send(&self, msg: #message_ty #all_generic_tys) -> Result<(), #error_ty> {
+                let mut sender = self.sender.clone();
+
+                self.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                sender.send((self.envelope(), msg)).await.map_err(|_| {
+                    self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    #error_ty::Stopped
+                })
+            }
+
+            #actor_methods
+
+            #constructors
+        }
+
+        impl #all_generics futures::Sink<#message_ty #all_generic_tys> for #actor_ty #all_generic_tys
+        {
+            type Error = #error_ty;
+
+            fn poll_ready(
+                self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                self.get_mut().sender.poll_ready(cx).map_err(|_| #error_ty::Stopped)
+            }
+
+            fn start_send(self: std::pin::Pin<&mut Self>, msg: #message_ty #all_generic_tys) -> Result<(), Self::Error> {
+                let this = self.get_mut();
+
+                this.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                this.sender.try_send((this.envelope(), msg)).map_err(|_| {
+                    this.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    #error_ty::Stopped
+                })
+            }
+
+            // Messages are handed to the mailbox in `start_send`, there's nothing to flush
+            fn poll_flush(
+                self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(
+                self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                std::task::Poll::Ready(Ok(()))
+            }
+        }
+
+        impl #all_generics std::fmt::Debug for #actor_ty #all_generic_tys
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!(#actor_ty))
+                 .field("actor_name", &self.actor_name)
+                 .finish()
+            }
+        }
+
+        impl #all_generics std::clone::Clone for #actor_ty #all_generic_tys
+        {
+            fn clone(&self) -> Self {
+                self.inner_rc.clone().fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                Self {
+                    sender: self.sender.clone(),
+                    inner_rc: self.inner_rc.clone(),
                     queue_len: self.queue_len.clone(),
+                    stopped: self.stopped.clone(),
                     actor_name: format!(
                         "{} {} {}",
                          stringify!(#actor_ty),
@@ -319,82 +1469,2216 @@ pub fn derive_actor(args: TokenStream, item: TokenStream) -> TokenStream
                      ),
                      actor_uuid: self.actor_uuid,
                      actor_num: self.actor_num + 1,
+                     deadline: self.deadline,
+                     dead_letters: self.dead_letters.clone(),
+                     stash: self.stash.clone(),
+                }
+            }
+        }
+
+        impl #all_generics Drop for #actor_ty #all_generic_tys
+        {
+            fn drop(&mut self) {
+                self.inner_rc.clone().fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        }
+
+        // Actor Join Handle
+        /// Resolves once the actor has stopped, with the reason it stopped and the `ActorImpl` it
+        /// owned. The `ActorImpl` is `None` if the actor was killed, or if a terminal message
+        /// consumed it.
+        pub struct #join_handle_ty #all_generics {
+            handle: tokio::task::JoinHandle<Result<(#exit_reason_ty, Option<#self_ty>), futures::future::Aborted>>,
+            shutdown: std::sync::Arc<tokio::sync::Notify>,
+            kill: futures::future::AbortHandle,
+        }
+
+        #impl_token #all_generics #join_handle_ty #all_generic_tys {
+            /// Stops the actor from taking new messages, it stops once it has handled those
+            /// already queued
+            pub fn shutdown(&self) {
+                self.shutdown.notify();
+            }
+
+            /// Stops the actor without handling the messages left in its mailbox
+            pub fn kill(&self) {
+                self.kill.abort();
+            }
+        }
+
+        impl #all_generics std::future::Future for #join_handle_ty #all_generic_tys
+        {
+            type Output = (#exit_reason_ty, Option<#self_ty>);
+
+            fn poll(
+                self: std::pin::Pin<&mut Self>,
+                cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Self::Output> {
+                match std::future::Future::poll(std::pin::Pin::new(&mut self.get_mut().handle), cx) {
+                    std::task::Poll::Ready(Ok(Ok(exit))) => std::task::Poll::Ready(exit),
+                    std::task::Poll::Ready(_) => std::task::Poll::Ready((#exit_reason_ty::Killed, None)),
+                    std::task::Poll::Pending => std::task::Poll::Pending,
+                }
+            }
+        }
+
+        pub enum #exit_reason_ty {
+            /// Every handle was dropped and the mailbox was drained
+            Normal,
+            /// A message handler panicked, the `ActorImpl` is left as the handler left it
+            Panicked(Box<dyn std::any::Any + Send>),
+            /// The actor was killed, or its task was cancelled by its runtime shutting down
+            Killed,
+            /// A handler's error was met with `Stop`, the `ActorImpl` is left as the handler left it
+            Failed(Box<dyn std::any::Any + Send>),
+        }
+
+        impl #exit_reason_ty {
+            pub fn is_normal(&self) -> bool {
+                match self {
+                    #exit_reason_ty::Normal => true,
+                    _ => false,
+                }
+            }
+        }
+
+        impl std::fmt::Debug for #exit_reason_ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    #exit_reason_ty::Normal => write!(f, "Normal"),
+                    #exit_reason_ty::Panicked(_) => write!(f, "Panicked(..)"),
+                    #exit_reason_ty::Killed => write!(f, "Killed"),
+                    #exit_reason_ty::Failed(_) => write!(f, "Failed(..)"),
+                }
+            }
+        }
+
+        // Weak Actor Struct
+        /// A handle to an actor that doesn't count towards keeping it alive, like `std::sync::Weak`
+        pub struct #weak_actor_ty #all_generics {
+            sender: Sender<(#envelope_ty, #message_ty #all_generic_tys)>,
+            inner_rc: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+            actor_name: String,
+            actor_uuid: uuid::Uuid,
+            actor_num: usize,
+            dead_letters: #dead_letters_ty,
+            stash: #shared_stash_ty,
+        }
+
+        #impl_token #all_generics #weak_actor_ty #all_generic_tys {
+            /// Returns a strong handle to the actor, or `None` if it has stopped
+            pub fn upgrade(&self) -> Option<#actor_ty #all_generic_tys> {
+                self.inner_rc.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                if self.is_stopped() {
+                    self.inner_rc.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    return None;
+                }
+
+                Some(#actor_ty {
+                    sender: self.sender.clone(),
+                    inner_rc: self.inner_rc.clone(),
+                    queue_len: self.queue_len.clone(),
+                    stopped: self.stopped.clone(),
+                    actor_name: format!(
+                        "{} {} {}",
+                         stringify!(#actor_ty),
+                         self.actor_uuid,
+                         self.actor_num + 1,
+                     ),
+                    actor_uuid: self.actor_uuid,
+                    actor_num: self.actor_num + 1,
+                    deadline: None,
+                    dead_letters: self.dead_letters.clone(),
+                    stash: self.stash.clone(),
+                })
+            }
+
+            /// Whether the actor has finished handling messages and shut down
+            pub fn is_stopped(&self) -> bool {
+                self.stopped.load(std::sync::atomic::Ordering::SeqCst)
+            }
+
+            /// Sends an already constructed message to the actor, failing if it has stopped
+            pub async fn send(&self, msg: #message_ty #all_generic_tys) -> Result<(), #error_ty> {
+                if self.is_stopped() {
+                    return Err(#error_ty::Stopped);
+                }
+
+                let mut sender = self.sender.clone();
+
+                self.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                let envelope = #envelope_ty::new(#envelope_ty::current_sender(), None);
+                sender.send((envelope, msg)).await.map_err(|_| {
+                    self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                    #error_ty::Stopped
+                })
+            }
+        }
+
+        impl #all_generics From<#actor_ty #all_generic_tys> for #weak_actor_ty #all_generic_tys
+        {
+            fn from(actor: #actor_ty #all_generic_tys) -> Self {
+                actor.downgrade()
+            }
+        }
+
+        impl #all_generics std::fmt::Debug for #weak_actor_ty #all_generic_tys
+        {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.debug_struct(stringify!(#weak_actor_ty))
+                 .field("actor_name", &self.actor_name)
+                 .finish()
+            }
+        }
+
+        impl #all_generics std::clone::Clone for #weak_actor_ty #all_generic_tys
+        {
+            fn clone(&self) -> Self {
+                Self {
+                    sender: self.sender.clone(),
+                    inner_rc: self.inner_rc.clone(),
+                    queue_len: self.queue_len.clone(),
+                    stopped: self.stopped.clone(),
+                    actor_name: self.actor_name.clone(),
+                    actor_uuid: self.actor_uuid,
+                    actor_num: self.actor_num,
+                    dead_letters: self.dead_letters.clone(),
+                    stash: self.stash.clone(),
+                }
+            }
+        }
+
+        // Actor Context
+        /// Passed to any handler that takes a `&mut` or `&` reference to it. Holding a context
+        /// does not keep the actor alive.
+        pub struct #context_ty #all_generics {
+            sender: Sender<(#envelope_ty, #message_ty #all_generic_tys)>,
+            queue_len: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+            stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+            dead_letters: #dead_letters_ty,
+            stash: #shared_stash_ty,
+            envelope: Option<#envelope_ty>,
+            intercepting: bool,
+        }
+
+        #impl_token #all_generics #context_ty #all_generic_tys {
+            fn new(
+                sender: &Sender<(#envelope_ty, #message_ty #all_generic_tys)>,
+                queue_len: &std::sync::Arc<std::sync::atomic::AtomicUsize>,
+                stopped: &std::sync::Arc<std::sync::atomic::AtomicBool>,
+                dead_letters: &#dead_letters_ty,
+                stash: &#shared_stash_ty,
+            ) -> Self {
+                Self {
+                    sender: sender.clone(),
+                    queue_len: queue_len.clone(),
+                    stopped: stopped.clone(),
+                    dead_letters: dead_letters.clone(),
+                    stash: stash.clone(),
+                    envelope: None,
+                    intercepting: false,
+                }
+            }
+
+            // A context for a handler called outside of an actor, messages sent to it are dropped
+            fn detached() -> Self {
+                let (sender, _) = channel(1);
+                Self {
+                    sender,
+                    queue_len: Default::default(),
+                    stopped: std::sync::Arc::new(std::sync::atomic::AtomicBool::new(true)),
+                    dead_letters: #dead_letters_ty::new(uuid::Uuid::nil()),
+                    stash: Default::default(),
+                    envelope: None,
+                    intercepting: false,
+                }
+            }
+
+            // A copy of this context for handling the message in `envelope`
+            fn for_envelope(&self, envelope: #envelope_ty) -> Self {
+                Self {
+                    sender: self.sender.clone(),
+                    queue_len: self.queue_len.clone(),
+                    stopped: self.stopped.clone(),
+                    dead_letters: self.dead_letters.clone(),
+                    stash: self.stash.clone(),
+                    envelope: Some(envelope),
+                    intercepting: false,
+                }
+            }
+
+            /// The envelope of the message being handled, `None` if the handler wasn't called
+            /// by the actor's router
+            pub fn envelope(&self) -> Option<&#envelope_ty> {
+                self.envelope.as_ref()
+            }
+
+            #state_methods
+
+            /// Puts `msg` aside until `unstash_all`, handing it back if the stash already holds as
+            /// many as it can. To stash the message being handled, take it in the ActorImpl's
+            /// `intercept` hook, where it keeps its envelope; other messages get a new one
+            pub fn stash(&self, msg: impl Into<#message_ty #all_generic_tys>) -> Result<(), #message_ty #all_generic_tys> {
+                let mut stash = self.stash.lock().unwrap_or_else(|e| e.into_inner());
+                if stash.stashed.len() >= #max_stash_size {
+                    return Err(msg.into());
+                }
+
+                let envelope = match &self.envelope {
+                    Some(envelope) if self.intercepting => envelope.clone(),
+                    _ => #envelope_ty::new(#envelope_ty::current_sender(), None),
+                };
+                stash.stashed.push_back((envelope, msg.into()));
+                Ok(())
+            }
+
+            /// Hands the stashed messages back to the actor, which routes them in the order they
+            /// were stashed, before the rest of its mailbox. Those whose caller has gone away or
+            /// whose deadline has passed become dead letters instead
+            pub fn unstash_all(&self) {
+                let mut stash = self.stash.lock().unwrap_or_else(|e| e.into_inner());
+                let (dropped, mut stashed): (std::collections::VecDeque<_>, _) = std::mem::take(&mut stash.stashed)
+                    .into_iter()
+                    .partition(|(envelope, _)| envelope.is_cancelled() || envelope.is_expired());
+                self.queue_len.fetch_add(stashed.len(), std::sync::atomic::Ordering::SeqCst);
+                stash.unstashed.append(&mut stashed);
+                drop(stash);
+
+                for (envelope, msg) in dropped {
+                    self.dead_letters.deliver(envelope, msg.method_name(), msg);
+                }
+            }
+
+            /// How many messages are stashed
+            pub fn stash_len(&self) -> usize {
+                self.stash.lock().unwrap_or_else(|e| e.into_inner()).stashed.len()
+            }
+
+            /// Whether the caller waiting on the message being handled has gone away, so any
+            /// reply would be dropped
+            pub fn is_cancelled(&self) -> bool {
+                self.envelope.as_ref().map_or(false, #envelope_ty::is_cancelled)
+            }
+
+            // Hands a fire-and-forget handler's error to the router running it
+            #[doc(hidden)]
+            pub fn handler_failed(&self, method: &'static str, error: Box<dyn std::any::Any + Send>) {
+                let mut failed = Some((method, error));
+                let _ = #handler_errors_key.try_with(|errors| errors.borrow_mut().extend(failed.take()));
+                if failed.is_some() {
+                    tracing::warn!(actor = stringify!(#actor_ty), method, "a fire-and-forget handler returned an error");
+                }
+            }
+
+            /// Sends `msg` to this actor once `delay` has passed, unless the returned handle
+            /// is aborted first
+            pub fn send_after(
+                &self,
+                delay: std::time::Duration,
+                msg: #message_ty #all_generic_tys,
+            ) -> futures::future::AbortHandle {
+                let mut sender = self.sender.clone();
+                let queue_len = self.queue_len.clone();
+                let dead_letters = self.dead_letters.clone();
+                let envelope = #envelope_ty::new(#envelope_ty::current_sender(), None);
+
+                // Only the wait is abortable, a send that's been counted in `queue_len` always completes
+                let (timer, registration) = futures::future::AbortHandle::new_pair();
+                let delay = futures::future::Abortable::new(tokio::time::delay_for(delay), registration);
+                tokio::task::spawn(async move {
+                    if delay.await.is_err() {
+                        return;
+                    }
+
+                    queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                    if let Err(e) = sender.send((envelope.for_next_message(), msg)).await {
+                        let (envelope, msg) = e.0;
+                        queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                        dead_letters.deliver(envelope, msg.method_name(), msg);
+                    }
+                });
+
+                timer
+            }
+
+            /// Sends a message built by `make_msg` to this actor every `period`, until the
+            /// returned handle is aborted or the actor stops. A zero `period` is treated as one millisecond
+            pub fn send_interval(
+                &self,
+                period: std::time::Duration,
+                mut make_msg: impl FnMut() -> #message_ty #all_generic_tys + Send + 'static,
+            ) -> futures::future::AbortHandle {
+                let mut sender = self.sender.clone();
+                let queue_len = self.queue_len.clone();
+                let stopped = self.stopped.clone();
+                let dead_letters = self.dead_letters.clone();
+                let envelope = #envelope_ty::new(#envelope_ty::current_sender(), None);
+
+                let period = std::cmp::max(period, std::time::Duration::from_millis(1));
+                let (timer, registration) = futures::future::AbortHandle::new_pair();
+                let interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                let mut ticks = futures::stream::Abortable::new(interval, registration);
+                tokio::task::spawn(async move {
+                    while futures::StreamExt::next(&mut ticks).await.is_some() {
+                        if stopped.load(std::sync::atomic::Ordering::SeqCst) {
+                            break;
+                        }
+
+                        queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                        if let Err(e) = sender.send((envelope.for_next_message(), make_msg())).await {
+                            let (envelope, msg) = e.0;
+                            queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                            dead_letters.deliver(envelope, msg.method_name(), msg);
+                            break;
+                        }
+                    }
+                });
+
+                timer
+            }
+        }
+
+        // Actor Error
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum #error_ty {
+            /// The actor is no longer accepting messages
+            Stopped,
+            /// The message was accepted, but dropped before a reply was sent
+            NoReply,
+            /// The call was made by a handler the actor would have to finish first
+            Deadlock,
+        }
+
+        impl std::fmt::Display for #error_ty {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                match self {
+                    #error_ty::Stopped => write!(f, "{} has stopped", stringify!(#actor_ty)),
+                    #error_ty::NoReply => write!(f, "{} dropped the message without replying", stringify!(#actor_ty)),
+                    #error_ty::Deadlock => write!(f, "{} was called by a handler it would have to wait on", stringify!(#actor_ty)),
+                }
+            }
+        }
+
+        impl std::error::Error for #error_ty {}
+
+        #interface
+
+        #system
+
+        #group
+
+        #pool
+
+        #shards
+
+        // Actor Probe
+        pub struct #probe_ty #all_generics {
+            recorded: tokio::sync::mpsc::UnboundedReceiver<#message_ty #all_generic_tys>,
+            responders: std::sync::Arc<std::sync::Mutex<Vec<
+                Box<dyn FnMut(&mut #message_ty #all_generic_tys) -> bool + Send>
+            >>>,
+        }
+
+        #impl_token #all_generics #probe_ty #all_generic_tys {
+            /// Waits for the next message sent to the probed actor. Returns `None` once
+            /// every handle to the probed actor has been dropped.
+            pub async fn recv(&mut self) -> Option<#message_ty #all_generic_tys> {
+                self.recorded.recv().await
+            }
+
+            /// Returns the next message sent to the probed actor, if one has already arrived
+            pub fn try_recv(&mut self) -> Option<#message_ty #all_generic_tys> {
+                self.recorded.try_recv().ok()
+            }
+
+            #probe_responders
+        }
+
+    };
+
+    // println!("{}", result);
+
+    result.into()
+}
+
+// A `group = ".."` impl block adds its messages to an Actor declared by another block with
+// `groups(..)`. Its messages are wrapped in one variant of the Actor's message type, so the Actor
+// keeps one mailbox, and its methods are added to the Actor in another inherent impl.
+fn gen_message_group(
+    args: &ActorArgs,
+    group: &syn::Ident,
+    o_input: syn::ItemImpl,
+    items: Vec<ImplItem>,
+    all_items: Vec<ImplItem>,
+    type_name: &str,
+    self_ty: &syn::Type,
+    generics: &syn::Generics,
+) -> impl quote::ToTokens {
+    let only_group = !(args.on_error || args.broadcast || args.pool || args.system || args.interface.is_some()
+        || args.max_batch_size.is_some() || args.max_batch_linger_ms.is_some() || args.max_concurrency.is_some()
+        || args.max_stash_size.is_some() || !args.groups.is_empty() || !args.states.is_empty() || (args.group.is_some() && args.state.is_some()));
+    if !only_group {
+        return syn::Error::new(group.span(), "a message group's block only takes `group = \"..\"` or `state = \"..\"`")
+            .to_compile_error();
+    }
+    if merged_generics(items.clone(), o_input.clone()).params.len() != generics.params.len() {
+        return syn::Error::new(self_ty.span(), "message groups can't have generic message methods")
+            .to_compile_error();
+    }
+    if let Err(e) = check_reserved(&items, type_name, &args) {
+        return e.to_compile_error();
+    }
+
+    let message_ty = format_ident!("{}Message", type_name);
+    let group_message_ty = format_ident!("{}{}Message", type_name, camel_case(&group.to_string()));
+    let actor_ty = format_ident!("{}Actor", type_name);
+    let error_ty = format_ident!("{}ActorError", type_name);
+    let context_ty = format_ident!("{}Context", type_name);
+    let join_handle_ty = format_ident!("{}ActorJoinHandle", type_name);
+    let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+    let constructors = gen_constructors(all_items, self_ty, &quote!(#join_handle_ty #ty_generics));
+
+    let message_variants = gen_message_variants(items.clone());
+    let method_names = gen_method_names(items.clone(), &group_message_ty);
+    let args_structs = gen_args_structs(items.clone(), type_name, &group_message_ty, generics);
+    let actor_methods = gen_actor_methods(items.clone(), &group_message_ty, &actor_ty, &error_ty);
+    let (route_arms, _, terminal_variants, _, concurrent_variants) = gen_route_arms(items, &group_message_ty, self_ty);
+
+    if !terminal_variants.is_empty() {
+        return syn::Error::new(group.span(), "terminal messages must be declared by the actor's main block")
+            .to_compile_error();
+    }
+    if !concurrent_variants.is_empty() {
+        return syn::Error::new(group.span(), "concurrent messages must be declared by the actor's main block")
+            .to_compile_error();
+    }
+
+    quote!(
+        #o_input
+
+        #[allow(non_camel_case_types)]
+        pub enum #group_message_ty #impl_generics {
+            #message_variants
+        }
+
+        #args_structs
+
+        impl #impl_generics From<#group_message_ty #ty_generics> for #message_ty #ty_generics {
+            fn from(message: #group_message_ty #ty_generics) -> Self {
+                #message_ty :: #group(message)
+            }
+        }
+
+        impl #impl_generics #group_message_ty #ty_generics {
+            /// The name of the method this message is handled by
+            pub fn method_name(&self) -> &'static str {
+                match self {
+                    #method_names
+                }
+            }
+
+            #[doc(hidden)]
+            pub async fn dispatch(self, actor_impl: &mut #self_ty, ctx: &mut #context_ty #ty_generics) {
+                let message = self;
+                match message {
+                    #route_arms
+                };
+            }
+        }
+
+        impl #impl_generics #actor_ty #ty_generics {
+            #actor_methods
+
+            #constructors
+        }
+    )
+}
+
+// `XActor::spawn_<ctor>` for every associated function marked `#[actor(constructor)]`, which
+// builds the ActorImpl and spawns its Actor in one step
+fn gen_constructors(
+    items: Vec<ImplItem>,
+    self_ty: &syn::Type,
+    join_handle_ty: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let mut constructors = quote!();
+
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if has_receiver(&method.sig) || !has_actor_attr(&method.attrs, "constructor") {
+                continue;
+            }
+
+            let ident = &method.sig.ident;
+            let spawn_ident = format_ident!("spawn_{}", ident);
+            let vis = &method.vis;
+            let (generics, where_clause) = (&method.sig.generics, &method.sig.generics.where_clause);
+            let (params, conversions) = actor_params(&method.sig);
+            let call_args = call_args(&method.sig);
+            let build = if method.sig.asyncness.is_some() {
+                quote!(<#self_ty>::#ident(#call_args).await)
+            } else {
+                quote!(<#self_ty>::#ident(#call_args))
+            };
+            let doc = format!("Builds the ActorImpl with `{}` and spawns its Actor", ident);
+
+            constructors.extend(quote!(
+                #[doc = #doc]
+                #vis async fn #spawn_ident #generics (#params) -> (Self, #join_handle_ty)
+                    #where_clause
+                {
+                    #conversions
+                    Self::new(#build).await
+                }
+            ));
+        }
+    }
+
+    constructors
+}
+
+// The methods an Actor gets for a block's message methods, each sends a `message_ty`, which
+// converts into the Actor's message type
+fn gen_actor_methods(
+    items: Vec<ImplItem>,
+    message_ty: &syn::Ident,
+    actor_ty: &syn::Ident,
+    error_ty: &syn::Ident,
+) -> impl quote::ToTokens {
+    let mut actor_methods = quote!();
+
+    for item in items.clone() {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                let ident = method.sig.ident.clone();
+
+                let (arg_and_tys, conversions) = actor_params(&method.sig);
+                let fields = message_fields(&method.sig);
+
+                let stream_reply_capacity = STREAM_REPLY_CAPACITY;
+                let reply_field = if fallible(&method.sig) {
+                    quote!(reply_to: Some(reply_to))
+                } else {
+                    quote!(reply_to)
+                };
+
+                let actor_method = match reply(&method.sig) {
+                    // Stream arguments are forwarded to the actor one message per item
+                    Reply::None if stream_arg(&method.sig).is_some() => {
+                        let (arg_name, arg_ty) = stream_arg(&method.sig).unwrap();
+
+                        if typed_args(&method.sig).len() != 1 {
+                            syn::Error::new(
+                                method.sig.span(),
+                                "an #[actor(stream)] argument must be the method's only argument",
+                            ).to_compile_error()
+                        } else {
+                            quote!(
+                                pub async fn #ident (&self, #arg_name: impl futures::Stream<Item = #arg_ty> + Send + 'static) {
+                                    // Holding a clone keeps the actor alive until the stream is exhausted
+                                    let actor = self.clone();
+                                    let envelope = self.envelope();
+
+                                    tokio::task::spawn(async move {
+                                        let mut stream = Box::pin(#arg_name);
+
+                                        while let Some(item) = futures::StreamExt::next(&mut stream).await {
+                                            let envelope = envelope.for_next_message();
+                                            let msg = #message_ty :: #ident { #arg_name: item };
+
+                                            let mut sender = actor.sender.clone();
+
+                                            actor.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                                            if let Err(e) = sender.send((envelope, msg.into())).await {
+                                                let (envelope, msg) = e.0;
+                                                actor.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                                                actor.dead_letters.deliver(envelope, msg.method_name(), msg);
+                                                break;
+                                            }
+                                        }
+                                    });
+                                }
+                            )
+                        }
+                    }
+                    Reply::None => quote!(
+
+                        // #[tracing::instrument(skip(self, #args))]
+                        pub async fn #ident (&self, #arg_and_tys) {
+                            // tracing::trace!("{}.{}", stringify!(#actor_ty), stringify!(#ident));
+                            #conversions
+
+                            let envelope = self.envelope();
+                            let msg = #message_ty :: #ident { #fields };
+
+                            let mut sender = self.sender.clone();
+
+                            let queue_len = self.queue_len.clone();
+                            let dead_letters = self.dead_letters.clone();
+
+                            queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                            let task = async move {
+                                if let Err(e) = sender.send((envelope, msg.into()))
+                                .await {
+                                    let (envelope, msg) = e.0;
+                                    queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                                    dead_letters.deliver(envelope, msg.method_name(), msg);
+                                }
+                            };
+
+                            // let span = tracing::info_span!(
+                            //     concat!(
+                            //         stringify!(#actor_ty),
+                            //         ".",
+                            //         stringify!(#ident)
+                            //     )
+                            // );
+                            let handle = tokio::task::spawn(
+                                // tracing::Instrument::instrument(
+                                    task,
+                                //     span
+                                // )
+                            );
+
+                        }
+                    ),
+                    // Request/response: the message carries a oneshot that the router
+                    // answers with the handler's return value
+                    Reply::Value(reply_ty) => quote!(
+                        pub async fn #ident (&self, #arg_and_tys) -> Result<#reply_ty, #error_ty> {
+                            #conversions
+                            let mut envelope = self.envelope().with_call_chain();
+                            if envelope.call_chain.contains(&self.actor_uuid) {
+                                return Err(#error_ty::Deadlock);
+                            }
+
+                            let (reply_to, reply) = futures::channel::oneshot::channel();
+
+                            // Dropping this call drops the token, which cancels the message
+                            let token = std::sync::Arc::new(());
+                            envelope.cancellation = Some(std::sync::Arc::downgrade(&token));
+
+                            let msg = #message_ty :: #ident { #fields #reply_field };
+
+                            let mut sender = self.sender.clone();
+
+                            self.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                            if sender.send((envelope, msg.into())).await.is_err() {
+                                self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                                return Err(#error_ty::Stopped);
+                            }
+
+                            let reply = reply.await;
+                            drop(token);
+                            reply.map_err(|_| #error_ty::NoReply)
+                        }
+                    ),
+                    // The router forwards the handler's stream over a bounded channel, if the
+                    // actor has stopped the returned stream is simply empty
+                    Reply::Stream(item_ty) => quote!(
+                        pub async fn #ident (&self, #arg_and_tys) -> futures::stream::BoxStream<'static, #item_ty> {
+                            #conversions
+                            let (reply_to, reply) = tokio::sync::mpsc::channel(#stream_reply_capacity);
+
+                            // The returned stream holds the token, dropping it cancels the message
+                            let token = std::sync::Arc::new(());
+                            let mut envelope = self.envelope();
+                            envelope.cancellation = Some(std::sync::Arc::downgrade(&token));
+
+                            let msg = #message_ty :: #ident { #fields reply_to };
+
+                            let mut sender = self.sender.clone();
+
+                            self.queue_len.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+
+                            if sender.send((envelope, msg.into())).await.is_err() {
+                                self.queue_len.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                            }
+
+                            Box::pin(futures::StreamExt::map(reply, move |item| {
+                                let _ = &token;
+                                item
+                            }))
+                        }
+                    ),
+                };
+
+                actor_methods.extend(actor_method);
+            }
+        }
+    }
+
+    actor_methods
+}
+
+fn gen_actor_system() -> impl quote::ToTokens {
+    quote!(
+        tokio::task_local! {
+            // The `system` actor whose handler is running in this task, after the `system` actors of
+            // any type whose handlers are waiting on it
+            static ACTOR_CALL_CHAIN: Vec<uuid::Uuid>;
+        }
+
+        /// A message that a `system` actor couldn't deliver, as handed to the `ActorSystem`'s dead letter sink
+        #[allow(dead_code)]
+        pub struct ActorDeadLetter {
+            /// The actor the message was sent to
+            pub actor_name: String,
+            /// The method the message was sent through
+            pub method: &'static str,
+            pub correlation_id: uuid::Uuid,
+            pub sender_id: Option<uuid::Uuid>,
+            /// The undelivered message, which `downcast`s to its actor's message type
+            pub message: Box<dyn std::any::Any + Send>,
+        }
+
+        // Used for `system` actors that have no sink of their own, nor one for their type
+        static ACTOR_DEAD_LETTERS: std::sync::RwLock<Option<std::sync::Arc<dyn Fn(ActorDeadLetter) + Send + Sync>>> =
+            std::sync::RwLock::new(None);
+
+        /// Implemented by the join handles of actors declared with `#[derive_actor(system)]`
+        #[allow(dead_code)]
+        pub trait ActorSystemJoinHandle: std::future::Future + Send + 'static {
+            /// The handle's `shutdown` and `kill`, usable once the handle itself is being awaited
+            fn controls(&self) -> (std::sync::Arc<dyn Fn() + Send + Sync>, std::sync::Arc<dyn Fn() + Send + Sync>);
+        }
+
+        #[allow(dead_code)]
+        #[derive(Clone)]
+        struct ActorSystemMember {
+            name: String,
+            shutdown: std::sync::Arc<dyn Fn() + Send + Sync>,
+            kill: std::sync::Arc<dyn Fn() + Send + Sync>,
+            stopped: futures::future::Shared<std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>>,
+        }
+
+        /// Owns a set of actors, so they can be joined or shut down together
+        #[allow(dead_code)]
+        #[derive(Default)]
+        pub struct ActorSystem {
+            // In the order they were spawned
+            members: std::sync::Mutex<Vec<ActorSystemMember>>,
+        }
+
+        #[allow(dead_code)]
+        impl ActorSystem {
+            pub fn new() -> Self {
+                Self::default()
+            }
+
+            /// Sends messages that actors declared with `system`, of any type, can no longer deliver
+            /// to `sink`, rather than logging them. Sinks set for one actor, or for its type, take precedence
+            pub fn set_default_dead_letters(sink: impl Fn(ActorDeadLetter) + Send + Sync + 'static) {
+                if let Ok(mut dead_letters) = ACTOR_DEAD_LETTERS.write() {
+                    *dead_letters = Some(std::sync::Arc::new(sink));
+                }
+            }
+
+            /// Takes ownership of the join handle of the actor `spawned` resolves to, e.g.
+            /// `system.spawn("cache", CacheActor::new(cache))`, and returns the actor. The handles of
+            /// actors that have stopped are let go of here and in `join_all`
+            pub async fn spawn<A, H: ActorSystemJoinHandle>(
+                &self,
+                name: impl Into<String>,
+                spawned: impl std::future::Future<Output = (A, H)>,
+            ) -> A {
+                let (actor, handle) = spawned.await;
+                let (shutdown, kill) = handle.controls();
+                let stopped: std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>> =
+                    Box::pin(futures::FutureExt::map(handle, |_| ()));
+
+                let mut members = self.members();
+                Self::prune(&mut members);
+                members.push(ActorSystemMember {
+                    name: name.into(),
+                    shutdown,
+                    kill,
+                    stopped: futures::FutureExt::shared(stopped),
+                });
+                actor
+            }
+
+            fn members(&self) -> std::sync::MutexGuard<'_, Vec<ActorSystemMember>> {
+                self.members.lock().unwrap_or_else(|e| e.into_inner())
+            }
+
+            // Lets go of the actors that have stopped
+            fn prune(members: &mut Vec<ActorSystemMember>) {
+                members.retain(|member| futures::FutureExt::now_or_never(member.stopped.clone()).is_none());
+            }
+
+            /// Waits for every actor spawned so far to stop
+            pub async fn join_all(&self) {
+                let members = self.members().clone();
+                futures::future::join_all(members.into_iter().map(|member| member.stopped)).await;
+                Self::prune(&mut self.members());
+            }
+
+            /// Shuts the actors down one at a time, most recently spawned first, so an actor can
+            /// still call on those spawned before it while it drains its mailbox. Whichever actors
+            /// haven't stopped by `timeout` are killed, and their names returned.
+            pub async fn shutdown(&self, timeout: std::time::Duration) -> Vec<String> {
+                let deadline = tokio::time::Instant::now() + timeout;
+                let mut members = self.members().clone();
+
+                while let Some(member) = members.pop() {
+                    (member.shutdown)();
+                    if tokio::time::timeout_at(deadline, member.stopped.clone()).await.is_err() {
+                        members.push(member);
+                        break;
+                    }
+                }
+
+                let stragglers: Vec<ActorSystemMember> = members.into_iter()
+                    .filter(|member| futures::FutureExt::now_or_never(member.stopped.clone()).is_none())
+                    .collect();
+                for member in stragglers.iter() {
+                    (member.kill)();
+                }
+                futures::future::join_all(stragglers.iter().map(|member| member.stopped.clone())).await;
+
+                stragglers.into_iter().map(|member| member.name).collect()
+            }
+
+            /// Runs `shutdown` once the process receives SIGINT or SIGTERM
+            pub fn shutdown_on_signals(self: &std::sync::Arc<Self>, timeout: std::time::Duration) {
+                let system = self.clone();
+                tokio::task::spawn(async move {
+                    let interrupt = Box::pin(async {
+                        if tokio::signal::ctrl_c().await.is_err() {
+                            futures::future::pending::<()>().await
+                        }
+                    });
+                    #[cfg(unix)]
+                    let terminate = Box::pin(async {
+                        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+                            Ok(mut terminate) => {
+                                terminate.recv().await;
+                            }
+                            Err(_) => futures::future::pending::<()>().await,
+                        }
+                    });
+                    #[cfg(not(unix))]
+                    let terminate = Box::pin(futures::future::pending::<()>());
+
+                    futures::future::select(interrupt, terminate).await;
+                    system.shutdown(timeout).await;
+                });
+            }
+        }
+    )
+}
+
+// The arms naming the method each `message_ty` variant is handled by
+fn gen_method_names(items: Vec<ImplItem>, message_ty: &syn::Ident) -> impl quote::ToTokens {
+    let mut method_names = quote!();
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(_) = method.vis {
+                let ident = method.sig.ident.clone();
+                method_names.extend(quote!(
+                    #message_ty :: #ident { .. } => stringify!(#ident),
+                ));
+            }
+        }
+    }
+
+    method_names
+}
+
+// The arms routing a `message_ty` to its handler, and separately those for terminal messages,
+// which take the ActorImpl by value
+fn gen_route_arms(
+    items: Vec<ImplItem>,
+    message_ty: &syn::Ident,
+    self_ty: &syn::Type,
+) -> (
+    impl quote::ToTokens,
+    impl quote::ToTokens,
+    Vec<impl quote::ToTokens>,
+    impl quote::ToTokens,
+    Vec<impl quote::ToTokens>,
+) {
+    let mut route_arms = quote!();
+    let mut terminal_arms = quote!();
+    let mut terminal_variants = Vec::new();
+    let mut concurrent_arms = quote!();
+    let mut concurrent_variants = Vec::new();
+
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(ref vis) = method.vis {
+                let ident = method.sig.ident.clone();
+
+                let mut args = quote![];
+                for (arg_name, _) in typed_args(&method.sig) {
+                    args.extend(quote!(#arg_name, ));
+                }
+                let call_args = call_args(&method.sig);
+
+                let receiver = match self_receiver(&method.sig) {
+                    SelfReceiver::Boxed => quote!(Box::new(actor_impl)),
+                    _ => quote!(actor_impl),
+                };
+                let call = if method.sig.asyncness.is_some() {
+                    quote!(#receiver. #ident (#call_args) .await)
+                } else {
+                    quote!(#receiver. #ident (#call_args))
+                };
+
+                let arm = match reply(&method.sig) {
+                    Reply::None => quote!(
+                        #message_ty :: #ident { #args } => {
+                            #call;
+                        },
+                    ),
+                    Reply::Value(_) if fallible(&method.sig) => quote!(
+                        #message_ty :: #ident { #args reply_to } => {
+                            let result = #call;
+                            match reply_to {
+                                Some(reply_to) => {
+                                    let _ = reply_to.send(result);
+                                }
+                                // Nobody is waiting on the result, so an error goes to the error policy
+                                None => if let Err(error) = result {
+                                    ctx.handler_failed(stringify!(#ident), Box::new(error));
+                                },
+                            }
+                        },
+                    ),
+                    Reply::Value(_) => quote!(
+                        #message_ty :: #ident { #args reply_to } => {
+                            let _ = reply_to.send(#call);
+                        },
+                    ),
+                    // Draining the stream happens off the actor so it can keep handling messages
+                    Reply::Stream(_) => quote!(
+                        #message_ty :: #ident { #args mut reply_to } => {
+                            let stream = #call;
+                            tokio::task::spawn(async move {
+                                let mut stream = Box::pin(stream);
+                                while let Some(item) = futures::StreamExt::next(&mut stream).await {
+                                    if reply_to.send(item).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                        },
+                    ),
+                };
+
+                // Concurrent handlers can also be routed with a shared ActorImpl
+                if has_actor_attr(&method.attrs, "concurrent") {
+                    concurrent_arms.extend(arm.clone());
+                    concurrent_variants.push(quote!(#message_ty :: #ident { .. }));
+                }
+
+                // Handlers that consume the ActorImpl are only routed once the actor has shut down
+                if self_receiver(&method.sig) == SelfReceiver::Ref {
+                    route_arms.extend(arm);
+                } else {
+                    terminal_arms.extend(arm);
+                    terminal_variants.push(quote!(#message_ty :: #ident { .. }));
+                    route_arms.extend(quote!(
+                        #message_ty :: #ident { .. } => panic!(concat!(
+                            stringify!(#self_ty), "::", stringify!(#ident),
+                            " consumes the ActorImpl, and can only be routed by its Actor"
+                        )),
+                    ));
+                }
+            }
+        }
+    }
+
+    (route_arms, terminal_arms, terminal_variants, concurrent_arms, concurrent_variants)
+}
+
+/// `#[actor(concurrent)]` handlers only get a shared ActorImpl and context, so they must take `&self`
+fn check_concurrent(items: &[ImplItem]) -> syn::Result<()> {
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if !has_actor_attr(&method.attrs, "concurrent") {
+                continue;
+            }
+
+            let shared_self = match method.sig.inputs.first() {
+                Some(FnArg::Receiver(receiver)) => receiver.reference.is_some() && receiver.mutability.is_none(),
+                _ => false,
+            };
+            let mut_context = method.sig.inputs.iter().any(|arg| match arg {
+                FnArg::Typed(arg) => match &*arg.ty {
+                    syn::Type::Reference(reference) => reference.mutability.is_some() && is_context_arg(arg),
+                    _ => false,
+                },
+                _ => false,
+            });
+
+            if !shared_self || mut_context {
+                return Err(syn::Error::new(
+                    method.sig.span(),
+                    "concurrent handlers must take `&self` and at most a shared context",
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Message methods are added to the Actor handle, so they can't share a name with its own methods
+/// Rejects message methods named after a method of the Actor handle, or of the group, pool or shards
+/// generated for them
+///
+/// ```compile_fail
+/// # use async_trait::async_trait;
+/// # use derive_aktor::derive_actor;
+/// # use tokio::sync::mpsc::{channel, Sender};
+/// # pub struct Greeter { self_actor: Option<GreeterActor> }
+/// #[derive_actor]
+/// impl Greeter {
+///     pub fn queue_len(&self) -> usize { 0 }
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # use async_trait::async_trait;
+/// # use derive_aktor::derive_actor;
+/// # use tokio::sync::mpsc::{channel, Sender};
+/// # pub struct Greeter { self_actor: Option<GreeterActor> }
+/// #[derive_actor(broadcast)]
+/// impl Greeter {
+///     pub fn len(&self) -> usize { 0 }
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # use async_trait::async_trait;
+/// # use derive_aktor::derive_actor;
+/// # use tokio::sync::mpsc::{channel, Sender};
+/// # pub struct Greeter { self_actor: Option<GreeterActor> }
+/// #[derive_actor(pool)]
+/// impl Greeter {
+///     pub fn resize(&self, size: usize) {}
+/// }
+/// # fn main() {}
+/// ```
+///
+/// ```compile_fail
+/// # use async_trait::async_trait;
+/// # use derive_aktor::derive_actor;
+/// # use tokio::sync::mpsc::{channel, Sender};
+/// # pub struct Greeter { self_actor: Option<GreeterActor> }
+/// #[derive_actor]
+/// impl Greeter {
+///     pub fn shard_for(&self, #[actor(shard_key)] name: String) {}
+/// }
+/// # fn main() {}
+/// ```
+///
+/// The same names are fine when the feature that generates them isn't used
+///
+/// ```
+/// # use async_trait::async_trait;
+/// # use derive_aktor::derive_actor;
+/// # use tokio::sync::mpsc::{channel, Sender};
+/// # pub struct Greeter { self_actor: Option<GreeterActor> }
+/// #[derive_actor]
+/// impl Greeter {
+///     pub fn len(&self) -> usize { 0 }
+///     pub fn resize(&self, size: usize) {}
+///     pub fn shard_for(&self, name: String) {}
+/// }
+/// # fn main() {}
+/// ```
+fn check_reserved(items: &[ImplItem], type_name: &str, args: &ActorArgs) -> syn::Result<()> {
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            let ident = &method.sig.ident;
+            if let Visibility::Public(_) = method.vis {
+                let is_sharded = method.sig.inputs.iter().any(|arg| match arg {
+                    FnArg::Typed(arg) => has_actor_attr(&arg.attrs, "shard_key"),
+                    _ => false,
+                });
+
+                let owner = if RESERVED_METHODS.iter().any(|reserved| ident == reserved) {
+                    "every Actor handle".to_owned()
+                } else if args.broadcast && GROUP_METHODS.iter().any(|reserved| ident == reserved) {
+                    format!("`{}ActorGroup`", type_name)
+                } else if args.pool && POOL_METHODS.iter().any(|reserved| ident == reserved) {
+                    format!("`{}ActorPool`", type_name)
+                } else if is_sharded && SHARDS_METHODS.iter().any(|reserved| ident == reserved) {
+                    format!("`{}ActorShards`", type_name)
+                } else {
+                    continue;
+                };
+                return Err(syn::Error::new(
+                    ident.span(),
+                    format!("`{}` is already a method of {}, rename the message method", ident, owner),
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn capitalize(s: &str) -> String {
+    let char_0 = &s[0..1].to_uppercase();
+
+    format!("{}{}", char_0, &s[1..])
+}
+
+fn gen_message_variants(items: Vec<ImplItem>) -> impl quote::ToTokens {
+    let mut message_variants = quote!();
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                let ident = method.sig.ident.clone();
+
+                let mut args = quote![];
+
+                for (arg_name, arg_ty) in typed_args(&method.sig) {
+                    args.extend(quote!(#arg_name: #arg_ty, ))
+                }
+
+                match reply(&method.sig) {
+                    Reply::None => {}
+                    Reply::Value(reply_ty) if fallible(&method.sig) => {
+                        args.extend(quote!(
+                            reply_to: Option<futures::channel::oneshot::Sender<#reply_ty>>,
+                        ))
+                    }
+                    Reply::Value(reply_ty) => {
+                        args.extend(quote!(
+                            reply_to: futures::channel::oneshot::Sender<#reply_ty>,
+                        ))
+                    }
+                    Reply::Stream(item_ty) => {
+                        args.extend(quote!(
+                            reply_to: tokio::sync::mpsc::Sender<#item_ty>,
+                        ))
+                    }
+                }
+
+                let variant = quote!(
+                    #ident {
+                        #args
+                    },
+                );
+
+
+                message_variants.extend(variant);
+            }
+        }
+    }
+
+    message_variants
+}
+
+// Generates a `<Type><Method>Args` struct for every fire-and-forget method, which converts `Into`
+// its message so that pipelines of arguments can be forwarded into the Actor's `Sink`. Fallible
+// methods get one too, their messages are sent without a `reply_to`
+fn gen_args_structs(
+    items: Vec<ImplItem>,
+    type_name: &str,
+    message_ty: &syn::Ident,
+    all_generics: &syn::Generics,
+) -> impl quote::ToTokens {
+    let (impl_generics, all_generic_tys, _) = all_generics.split_for_impl();
+
+    let mut args_structs = quote!();
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                let reply_field = match reply(&method.sig) {
+                    Reply::None => quote!(),
+                    Reply::Value(_) if fallible(&method.sig) => quote!(reply_to: None),
+                    Reply::Value(_) | Reply::Stream(_) => continue,
+                };
+
+                let ident = method.sig.ident.clone();
+                let args_ty = format_ident!("{}{}Args", type_name, camel_case(&ident.to_string()));
+
+                let typed_args = typed_args(&method.sig);
+                let arg_tys: Vec<syn::Type> = typed_args.iter().map(|(_, arg_ty)| arg_ty.clone()).collect();
+
+                let args_generics = used_generics(all_generics, &arg_tys);
+                let (_, args_generic_tys, _) = args_generics.split_for_impl();
+
+                let mut args = quote![];
+                let mut fields = quote![];
+                for (arg_name, arg_ty) in typed_args {
+                    args.extend(quote!(#arg_name, ));
+                    fields.extend(quote!(pub #arg_name: #arg_ty, ));
+                }
+
+                let doc = format!("The arguments of `{}`, which convert into its message", ident);
+
+                args_structs.extend(quote!(
+                    #[doc = #doc]
+                    pub struct #args_ty #args_generics {
+                        #fields
+                    }
+
+                    impl #impl_generics From<#args_ty #args_generic_tys> for #message_ty #all_generic_tys {
+                        fn from(args: #args_ty #args_generic_tys) -> Self {
+                            let #args_ty { #args } = args;
+                            #message_ty :: #ident { #args #reply_field }
+                        }
+                    }
+                ));
+            }
+        }
+    }
+
+    args_structs
+}
+
+// Generates an `on_<method>` scripting hook on the probe for every method that replies
+fn gen_probe_responders(
+    items: Vec<ImplItem>,
+    message_ty: &syn::Ident,
+    all_generic_tys: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let mut responders = quote!();
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                let ident = method.sig.ident.clone();
+                let on_ident = format_ident!("on_{}", ident);
+
+                let mut args = quote![];
+                let mut arg_refs = quote![];
+                for (arg_name, arg_ty) in typed_args(&method.sig) {
+                    args.extend(quote!(#arg_name, ));
+                    arg_refs.extend(quote!(&#arg_ty, ));
+                }
+
+                // The recorded message keeps a dangling reply_to so it can still be
+                // handed to the test intact
+                let (respond_ty, send_reply) = match reply(&method.sig) {
+                    Reply::None => continue,
+                    Reply::Value(reply_ty) if fallible(&method.sig) => (
+                        quote!(#reply_ty),
+                        quote!(
+                            if let Some(reply_to) = reply_to {
+                                let (dangling, _) = futures::channel::oneshot::channel();
+                                let _ = std::mem::replace(reply_to, dangling).send(reply);
+                            }
+                        ),
+                    ),
+                    Reply::Value(reply_ty) => (
+                        quote!(#reply_ty),
+                        quote!(
+                            let (dangling, _) = futures::channel::oneshot::channel();
+                            let _ = std::mem::replace(reply_to, dangling).send(reply);
+                        ),
+                    ),
+                    Reply::Stream(item_ty) => (
+                        quote!(Vec<#item_ty>),
+                        quote!(
+                            let (dangling, _) = tokio::sync::mpsc::channel(1);
+                            let mut reply_to = std::mem::replace(reply_to, dangling);
+                            tokio::task::spawn(async move {
+                                for item in reply {
+                                    if reply_to.send(item).await.is_err() {
+                                        break;
+                                    }
+                                }
+                            });
+                        ),
+                    ),
+                };
+
+                let doc = format!(
+                    "Answers every `{}` call with the value returned by `respond`",
+                    ident
+                );
+
+                responders.extend(quote!(
+                    #[doc = #doc]
+                    pub fn #on_ident (
+                        &self,
+                        mut respond: impl FnMut(#arg_refs) -> #respond_ty + Send + 'static,
+                    ) {
+                        let responder = move |msg: &mut #message_ty #all_generic_tys| {
+                            if let #message_ty :: #ident { #args reply_to } = msg {
+                                let reply = respond(#args);
+                                #send_reply
+                                true
+                            } else {
+                                false
+                            }
+                        };
+                        self.responders.lock().unwrap().push(Box::new(responder));
+                    }
+                ));
+            }
+        }
+    }
+
+    responders
+}
+
+// Generates the `broadcast` group, which fans every call out to all of its live members
+fn gen_group(
+    items: Vec<ImplItem>,
+    type_name: &str,
+    actor_ty: &syn::Ident,
+    error_ty: &syn::Ident,
+    all_generics: &impl quote::ToTokens,
+    all_generic_tys: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let group_ty = format_ident!("{}ActorGroup", type_name);
+    let weak_actor_ty = format_ident!("Weak{}Actor", type_name);
+
+    let mut group_methods = quote!();
+
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                // Fanning out a stream argument would mean teeing it, which isn't supported
+                if stream_arg(&method.sig).is_some() {
+                    continue;
+                }
+
+                let ident = method.sig.ident.clone();
+
+                let (arg_and_tys, conversions) = actor_params(&method.sig);
+
+                // Borrowed arguments are passed along as they are, the rest are cloned per member
+                let mut cloned_args = quote![];
+                let mut clone_bounds = quote![];
+                for (index, arg) in method.sig.inputs.iter().enumerate() {
+                    match arg {
+                        FnArg::Typed(arg) if is_context_arg(arg) || is_self_arg(arg) => (),
+                        FnArg::Typed(arg) if shared_ref_elem(&arg.ty).is_some() => {
+                            let arg_name = arg_name(index, arg);
+                            cloned_args.extend(quote!(#arg_name, ));
+                        }
+                        FnArg::Typed(arg) => {
+                            let (arg_name, arg_ty) = (arg_name(index, arg), &arg.ty);
+                            cloned_args.extend(quote!(#arg_name.clone(), ));
+                            clone_bounds.extend(quote!(#arg_ty: Clone, ));
+                        }
+                        FnArg::Receiver(_) => (),
+                    }
+                }
+
+                let group_method = match reply(&method.sig) {
+                    Reply::None => quote!(
+                        pub async fn #ident (&self, #arg_and_tys) where #clone_bounds {
+                            #conversions
+                            for member in self.live_members() {
+                                member.#ident(#cloned_args).await;
+                            }
+                        }
+                    ),
+                    Reply::Value(reply_ty) => quote!(
+                        pub async fn #ident (&self, #arg_and_tys) -> Vec<Result<#reply_ty, #error_ty>>
+                            where #clone_bounds
+                        {
+                            #conversions
+                            let members = self.live_members();
+                            let calls = members.iter().map(|member| member.#ident(#cloned_args));
+                            futures::future::join_all(calls).await
+                        }
+                    ),
+                    Reply::Stream(item_ty) => quote!(
+                        pub async fn #ident (&self, #arg_and_tys) -> futures::stream::BoxStream<'static, #item_ty>
+                            where #clone_bounds
+                        {
+                            #conversions
+                            let members = self.live_members();
+                            let calls = members.iter().map(|member| member.#ident(#cloned_args));
+                            let streams = futures::future::join_all(calls).await;
+                            Box::pin(futures::stream::select_all(streams))
+                        }
+                    ),
+                };
+
+                group_methods.extend(group_method);
+            }
+        }
+    }
+
+    quote!(
+        // Actor Group
+        /// Only holds weak handles, so being in a group doesn't keep an actor alive
+        pub struct #group_ty #all_generics {
+            members: std::sync::Mutex<Vec<#weak_actor_ty #all_generic_tys>>,
+        }
+
+        impl #all_generics #group_ty #all_generic_tys {
+            pub fn new() -> Self {
+                Self {
+                    members: std::sync::Mutex::new(Vec::new()),
                 }
             }
+
+            /// Adds an actor to the group, it's removed again once it stops. The group doesn't keep it
+            /// alive, so an actor whose only handle is given here stops right away
+            pub fn join(&self, member: impl Into<#weak_actor_ty #all_generic_tys>) {
+                self.members.lock().unwrap().push(member.into());
+            }
+
+            /// The number of members that haven't stopped
+            pub fn len(&self) -> usize {
+                self.live_members().len()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            // Drops members that have stopped, and returns strong handles to the rest
+            fn live_members(&self) -> Vec<#actor_ty #all_generic_tys> {
+                let mut members = self.members.lock().unwrap();
+                let mut live = Vec::with_capacity(members.len());
+                members.retain(|member| match member.upgrade() {
+                    Some(member) => {
+                        live.push(member);
+                        true
+                    }
+                    None => false,
+                });
+                live
+            }
+
+            #group_methods
         }
 
-        impl #all_generics Drop for #actor_ty #all_generic_tys
-        {
-            fn drop(&mut self) {
-                self.inner_rc.clone().fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+        impl #all_generics Default for #group_ty #all_generic_tys {
+            fn default() -> Self {
+                Self::new()
             }
         }
 
-    };
+        impl #all_generics std::iter::FromIterator<#actor_ty #all_generic_tys> for #group_ty #all_generic_tys {
+            fn from_iter<I: IntoIterator<Item = #actor_ty #all_generic_tys>>(members: I) -> Self {
+                Self {
+                    members: std::sync::Mutex::new(members.into_iter().map(Into::into).collect()),
+                }
+            }
+        }
+    )
+}
 
-    // println!("{}", result);
+// Generates the `pool`, which routes every call to one of its members
+fn gen_pool(
+    items: Vec<ImplItem>,
+    type_name: &str,
+    self_ty: &syn::Type,
+    actor_ty: &syn::Ident,
+    error_ty: &syn::Ident,
+    all_generics: &impl quote::ToTokens,
+    all_generic_tys: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let pool_ty = format_ident!("{}ActorPool", type_name);
+    let strategy_ty = format_ident!("{}PoolStrategy", type_name);
+    let join_handle_ty = format_ident!("{}ActorJoinHandle", type_name);
+
+    let mut pool_methods = quote!();
 
-    result.into()
-}
+    for item in items {
+        if let ImplItem::Method(method) = item {
+            if let Visibility::Public(vis) = method.vis {
+                let ident = method.sig.ident.clone();
 
-fn capitalize(s: &str) -> String {
-    let char_0 = &s[0..1].to_uppercase();
+                let (args, arg_and_tys, conversions, output, no_member) = routed_method_sig(&method.sig, error_ty);
 
-    format!("{}{}", char_0, &s[1..])
+                pool_methods.extend(quote!(
+                    pub async fn #ident (&self, #arg_and_tys) #output {
+                        #conversions
+                        match self.pick() {
+                            Some(member) => member.#ident(#args).await,
+                            None => #no_member,
+                        }
+                    }
+                ));
+            }
+        }
+    }
+
+    quote!(
+        // Actor Pool
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum #strategy_ty {
+            /// Each member in turn
+            RoundRobin,
+            /// A randomly chosen member
+            Random,
+            /// The member with the fewest queued messages
+            LeastLoaded,
+        }
+
+        pub struct #pool_ty #all_generics {
+            members: std::sync::Mutex<Vec<#actor_ty #all_generic_tys>>,
+            handles: std::sync::Mutex<Vec<#join_handle_ty #all_generic_tys>>,
+            factory: std::sync::Mutex<Box<dyn FnMut() -> #self_ty + Send>>,
+            strategy: std::sync::Mutex<#strategy_ty>,
+            next: std::sync::atomic::AtomicUsize,
+        }
+
+        impl #all_generics #pool_ty #all_generic_tys {
+            /// Spawns `size` actors, each built by `factory`, and routes calls round-robin
+            pub async fn new(size: usize, factory: impl FnMut() -> #self_ty + Send + 'static) -> Self {
+                let pool = Self {
+                    members: std::sync::Mutex::new(Vec::new()),
+                    handles: std::sync::Mutex::new(Vec::new()),
+                    factory: std::sync::Mutex::new(Box::new(factory)),
+                    strategy: std::sync::Mutex::new(#strategy_ty::RoundRobin),
+                    next: std::sync::atomic::AtomicUsize::new(0),
+                };
+                pool.resize(size).await;
+                pool
+            }
+
+            pub fn set_strategy(&self, strategy: #strategy_ty) {
+                *self.strategy.lock().unwrap() = strategy;
+            }
+
+            /// The number of members that haven't stopped
+            pub fn len(&self) -> usize {
+                self.live_members().len()
+            }
+
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            /// Grows the pool with actors from the factory, or shrinks it by letting go of
+            /// members, which stop once they've handled their queued messages
+            pub async fn resize(&self, size: usize) {
+                let len = self.len();
+
+                if size < len {
+                    self.members.lock().unwrap().truncate(size);
+                    return;
+                }
+
+                let actor_impls: Vec<#self_ty> = {
+                    let mut factory = self.factory.lock().unwrap();
+                    (len..size).map(|_| factory()).collect()
+                };
+
+                for actor_impl in actor_impls {
+                    let (member, handle) = #actor_ty::new(actor_impl).await;
+                    self.members.lock().unwrap().push(member);
+                    self.handles.lock().unwrap().push(handle);
+                }
+            }
+
+            /// Lets go of every member and waits for all of them to stop
+            pub async fn join(self) {
+                drop(self.members);
+                let handles = self.handles.into_inner().unwrap();
+                for handle in handles {
+                    let _ = handle.await;
+                }
+            }
+
+            // Drops members that have stopped, and returns handles to the rest
+            fn live_members(&self) -> Vec<#actor_ty #all_generic_tys> {
+                let mut members = self.members.lock().unwrap();
+                members.retain(|member| !member.is_stopped());
+                members.clone()
+            }
+
+            fn pick(&self) -> Option<#actor_ty #all_generic_tys> {
+                let members = self.live_members();
+                if members.is_empty() {
+                    return None;
+                }
+
+                let strategy = *self.strategy.lock().unwrap();
+                let member = match strategy {
+                    #strategy_ty::RoundRobin => {
+                        let next = self.next.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                        &members[next % members.len()]
+                    }
+                    #strategy_ty::Random => {
+                        let random = uuid::Uuid::new_v4().as_u128() as usize;
+                        &members[random % members.len()]
+                    }
+                    #strategy_ty::LeastLoaded => {
+                        members.iter().min_by_key(|member| member.queue_len()).unwrap()
+                    }
+                };
+
+                Some(member.clone())
+            }
+
+            #pool_methods
+        }
+    )
 }
 
-fn gen_message_variants(items: Vec<ImplItem>) -> impl quote::ToTokens {
-    let mut message_variants = quote!();
+// Generates a sharded handle when any method has an `#[actor(shard_key)]` argument. Keyed calls
+// are routed with a consistent hash ring, so adding or removing a shard only moves the keys of
+// its neighbours on the ring.
+fn gen_shards(
+    items: Vec<ImplItem>,
+    type_name: &str,
+    actor_ty: &syn::Ident,
+    error_ty: &syn::Ident,
+    all_generics: &impl quote::ToTokens,
+    all_generic_tys: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let shards_ty = format_ident!("{}ActorShards", type_name);
+    let virtual_nodes = SHARD_VIRTUAL_NODES;
+
+    let mut shard_methods = quote!();
+
     for item in items {
         if let ImplItem::Method(method) = item {
             if let Visibility::Public(vis) = method.vis {
-                let ident = method.sig.ident;
+                let shard_key = method.sig.inputs.iter().enumerate().find_map(|(index, arg)| match arg {
+                    FnArg::Typed(arg) if has_actor_attr(&arg.attrs, "shard_key") => {
+                        Some((arg_name(index, arg), arg.ty.clone()))
+                    }
+                    _ => None,
+                });
+                let (key_name, key_ty) = match shard_key {
+                    Some(key) => key,
+                    None => continue,
+                };
 
-                let mut args = quote![];
+                let ident = method.sig.ident.clone();
+                let (args, arg_and_tys, conversions, output, no_shard) = routed_method_sig(&method.sig, error_ty);
 
-                for arg in method.sig.inputs {
-                    let arg: FnArg = arg;
-                    match arg {
-                        FnArg::Receiver(_) => {
-                            continue;
+                // A borrowed key hashes the same as the value it borrows
+                let (key_ty, key) = match shared_ref_elem(&key_ty) {
+                    Some(elem) => (quote!(#elem), quote!(#key_name)),
+                    None => (quote!(#key_ty), quote!(&#key_name)),
+                };
+
+                shard_methods.extend(quote!(
+                    pub async fn #ident (&self, #arg_and_tys) #output where #key_ty: std::hash::Hash {
+                        #conversions
+                        match self.shard_for(#key) {
+                            Some(shard) => shard.#ident(#args).await,
+                            None => #no_shard,
                         }
-                        arg => args.extend(quote!(#arg, ))
                     }
+                ));
+            }
+        }
+    }
+
+    if shard_methods.is_empty() {
+        return quote!();
+    }
+
+    quote!(
+        // Actor Shards
+        pub struct #shards_ty #all_generics {
+            ring: std::sync::RwLock<std::collections::BTreeMap<u64, uuid::Uuid>>,
+            shards: std::sync::RwLock<std::collections::HashMap<uuid::Uuid, #actor_ty #all_generic_tys>>,
+        }
+
+        impl #all_generics #shards_ty #all_generic_tys {
+            pub fn new() -> Self {
+                Self {
+                    ring: Default::default(),
+                    shards: Default::default(),
                 }
+            }
 
+            pub fn add_shard(&self, shard: #actor_ty #all_generic_tys) {
+                let mut ring = self.ring.write().unwrap();
+                for node in 0..#virtual_nodes {
+                    ring.insert(Self::hash(&(shard.actor_uuid, node)), shard.actor_uuid);
+                }
+                self.shards.write().unwrap().insert(shard.actor_uuid, shard);
+            }
 
-                let variant = quote!(
-                    #ident {
-                        #args
-                    },
-                );
+            /// Removes the shard backed by the same actor as `shard`, its keys move to the
+            /// next shards on the ring
+            pub fn remove_shard(&self, shard: &#actor_ty #all_generic_tys) -> Option<#actor_ty #all_generic_tys> {
+                self.ring.write().unwrap().retain(|_, uuid| *uuid != shard.actor_uuid);
+                self.shards.write().unwrap().remove(&shard.actor_uuid)
+            }
 
+            pub fn len(&self) -> usize {
+                self.shards.read().unwrap().len()
+            }
 
-                message_variants.extend(variant);
+            pub fn is_empty(&self) -> bool {
+                self.len() == 0
+            }
+
+            /// The shard that owns `key`
+            pub fn shard_for<K: std::hash::Hash + ?Sized>(&self, key: &K) -> Option<#actor_ty #all_generic_tys> {
+                let hash = Self::hash(key);
+                let ring = self.ring.read().unwrap();
+                let uuid = ring.range(hash..).next()
+                    .or_else(|| ring.iter().next())
+                    .map(|(_, uuid)| *uuid)?;
+                self.shards.read().unwrap().get(&uuid).cloned()
             }
+
+            fn hash<K: std::hash::Hash + ?Sized>(key: &K) -> u64 {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                std::hash::Hash::hash(key, &mut hasher);
+                std::hash::Hasher::finish(&hasher)
+            }
+
+            #shard_methods
         }
-    }
 
-    message_variants
-}
+        impl #all_generics Default for #shards_ty #all_generic_tys {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
 
-fn all_generics(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote::ToTokens {
-    let impl_generics = item_impl.generics;
+        impl #all_generics std::iter::FromIterator<#actor_ty #all_generic_tys> for #shards_ty #all_generic_tys {
+            fn from_iter<I: IntoIterator<Item = #actor_ty #all_generic_tys>>(shards: I) -> Self {
+                let sharded = Self::new();
+                for shard in shards {
+                    sharded.add_shard(shard);
+                }
+                sharded
+            }
+        }
+    )
+}
 
-    let mut all_generics = impl_generics.clone();
+// Generates the `interface = ".."` trait, implemented by the Actor and by the ActorImpl behind a mutex
+fn gen_interface(
+    items: Vec<ImplItem>,
+    interface: &syn::Ident,
+    self_ty: &syn::Type,
+    actor_ty: &syn::Ident,
+    error_ty: &syn::Ident,
+    context_ty: &syn::Ident,
+    all_generics: &impl quote::ToTokens,
+    all_generic_tys: &impl quote::ToTokens,
+) -> impl quote::ToTokens {
+    let mut trait_methods = quote!();
+    let mut actor_impls = quote!();
+    let mut mutex_impls = quote!();
 
     for item in items {
         if let ImplItem::Method(method) = item {
             if let Visibility::Public(vis) = method.vis {
-                let mut generics = method.sig.generics;
-                let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+                // The in-process implementation can't hand the ActorImpl out of its Mutex
+                if self_receiver(&method.sig) != SelfReceiver::Ref {
+                    continue;
+                }
 
-                for param in generics.params {
-                    all_generics.params.push(param);
+                let ident = method.sig.ident.clone();
+
+                let (args, arg_and_tys, conversions, output, _) = routed_method_sig(&method.sig, error_ty);
+
+                let call_args = call_args(&method.sig);
+                let call = if method.sig.asyncness.is_some() {
+                    quote!(self.lock().await. #ident (#call_args) .await)
+                } else {
+                    quote!(self.lock().await. #ident (#call_args))
+                };
+
+                let mutex_body = match reply(&method.sig) {
+                    Reply::None if stream_arg(&method.sig).is_some() => {
+                        let (arg_name, _) = stream_arg(&method.sig).unwrap();
+                        quote!(
+                            let mut stream = Box::pin(#arg_name);
+                            while let Some(#arg_name) = futures::StreamExt::next(&mut stream).await {
+                                #call;
+                            }
+                        )
+                    }
+                    Reply::None => quote!(#call;),
+                    Reply::Value(_) => quote!(Ok(#call)),
+                    Reply::Stream(_) => quote!(Box::pin(#call)),
+                };
+
+                trait_methods.extend(quote!(
+                    async fn #ident (&self, #arg_and_tys) #output;
+                ));
+                actor_impls.extend(quote!(
+                    async fn #ident (&self, #arg_and_tys) #output {
+                        #actor_ty :: #ident (self, #args).await
+                    }
+                ));
+                mutex_impls.extend(quote!(
+                    async fn #ident (&self, #arg_and_tys) #output {
+                        #conversions
+                        let ctx = &mut #context_ty::detached();
+                        #mutex_body
+                    }
+                ));
+            }
+        }
+    }
+
+    quote!(
+        #[async_trait]
+        pub trait #interface #all_generics {
+            #trait_methods
+        }
+
+        #[async_trait]
+        impl #all_generics #interface #all_generic_tys for #actor_ty #all_generic_tys {
+            #actor_impls
+        }
+
+        // In-process implementation, the ActorImpl's `self_actor` is not set and messages
+        // sent through a context are dropped
+        #[async_trait]
+        impl #all_generics #interface #all_generic_tys for tokio::sync::Mutex<#self_ty> {
+            #mutex_impls
+        }
+    )
+}
+
+/// The arguments, parameters, parameter conversions and return type of a method that routes
+/// a call to one Actor, along with the value it returns when there is no Actor to route to
+fn routed_method_sig(
+    sig: &syn::Signature,
+    error_ty: &syn::Ident,
+) -> (
+    impl quote::ToTokens,
+    impl quote::ToTokens,
+    impl quote::ToTokens,
+    impl quote::ToTokens,
+    impl quote::ToTokens,
+) {
+    let mut args = quote![];
+    for (arg_name, _) in typed_args(sig) {
+        args.extend(quote!(#arg_name, ));
+    }
+    let (params, conversions) = actor_params(sig);
+    let mut arg_and_tys = quote!(#params);
+
+    // With no Actor to route to, calls behave as if sent to a stopped actor
+    let (output, stopped) = match reply(sig) {
+        Reply::None if stream_arg(sig).is_some() => {
+            let (arg_name, arg_ty) = stream_arg(sig).unwrap();
+            arg_and_tys = quote!(#arg_name: impl futures::Stream<Item = #arg_ty> + Send + 'static, );
+            (quote!(), quote!(()))
+        }
+        Reply::None => (quote!(), quote!(())),
+        Reply::Value(reply_ty) => (
+            quote!(-> Result<#reply_ty, #error_ty>),
+            quote!(Err(#error_ty::Stopped)),
+        ),
+        Reply::Stream(item_ty) => (
+            quote!(-> futures::stream::BoxStream<'static, #item_ty>),
+            quote!(Box::pin(futures::stream::empty())),
+        ),
+    };
+
+    (args, arg_and_tys, conversions, output, stopped)
+}
+
+/// The names and message field types of a method's arguments, skipping the receiver and
+/// any context
+fn typed_args(sig: &syn::Signature) -> Vec<(syn::Ident, syn::Type)> {
+    sig.inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(index, arg)| match arg {
+            FnArg::Typed(arg) if is_context_arg(arg) || is_self_arg(arg) => None,
+            FnArg::Typed(arg) => Some((arg_name(index, arg), owned_ty(&arg.ty))),
+            FnArg::Receiver(_) => None,
+        })
+        .collect()
+}
+
+/// The name an argument's message field and generated parameter go by. An identifier keeps its
+/// name, minus any `mut`, and other patterns get a synthetic `arg_<n>`. The handler is passed the
+/// field as is, so it still binds its own pattern.
+fn arg_name(index: usize, arg: &syn::PatType) -> syn::Ident {
+    match &*arg.pat {
+        syn::Pat::Ident(pat) if pat.subpat.is_none() => pat.ident.clone(),
+        _ => format_ident!("arg_{}", index),
+    }
+}
+
+/// Messages can't hold short-lived borrows, so a `&T` argument is stored as `T::Owned`
+fn owned_ty(ty: &syn::Type) -> syn::Type {
+    match shared_ref_elem(ty) {
+        Some(elem) => syn::parse_quote!(<#elem as std::borrow::ToOwned>::Owned),
+        None => ty.clone(),
+    }
+}
+
+/// The `T` in a `&T` argument, `&'static T` can be stored in a message as it is
+fn shared_ref_elem(ty: &syn::Type) -> Option<&syn::Type> {
+    match ty {
+        syn::Type::Reference(reference) if reference.mutability.is_none() => {
+            match reference.lifetime {
+                Some(ref lifetime) if lifetime.ident == "static" => None,
+                _ => Some(&reference.elem),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// The parameters that generated methods take in place of a message method's arguments, along
+/// with the statements that convert `#[actor(into)]` parameters to the argument's type
+fn actor_params(sig: &syn::Signature) -> (impl quote::ToTokens, impl quote::ToTokens) {
+    let mut params = quote![];
+    let mut conversions = quote![];
+    for (index, arg) in sig.inputs.iter().enumerate() {
+        match arg {
+            FnArg::Typed(arg) if is_context_arg(arg) || is_self_arg(arg) => (),
+            FnArg::Typed(arg) => {
+                let (arg_name, arg_ty) = (arg_name(index, arg), &arg.ty);
+                if has_actor_attr(&arg.attrs, "into") {
+                    params.extend(quote!(#arg_name: impl Into<#arg_ty> + Send, ));
+                    conversions.extend(quote!(let #arg_name: #arg_ty = #arg_name.into(); ));
+                } else {
+                    params.extend(quote!(#arg_name: #arg_ty, ));
+                }
+            }
+            FnArg::Receiver(_) => (),
+        }
+    }
+    (params, conversions)
+}
+
+/// The fields of a method's message, built from its converted parameters
+fn message_fields(sig: &syn::Signature) -> impl quote::ToTokens {
+    let mut fields = quote![];
+    for (index, arg) in sig.inputs.iter().enumerate() {
+        match arg {
+            FnArg::Typed(arg) if is_context_arg(arg) || is_self_arg(arg) => (),
+            FnArg::Typed(arg) => {
+                let arg_name = arg_name(index, arg);
+                if shared_ref_elem(&arg.ty).is_some() {
+                    fields.extend(quote!(#arg_name: std::borrow::ToOwned::to_owned(#arg_name), ));
+                } else {
+                    fields.extend(quote!(#arg_name, ));
+                }
+            }
+            FnArg::Receiver(_) => (),
+        }
+    }
+    fields
+}
+
+/// The arguments to call a handler with, passing the router's `ctx` in place of its context
+fn call_args(sig: &syn::Signature) -> impl quote::ToTokens {
+    let mut args = quote![];
+    for (index, arg) in sig.inputs.iter().enumerate() {
+        match arg {
+            FnArg::Typed(arg) if is_self_arg(arg) => (),
+            FnArg::Typed(arg) if is_context_arg(arg) => args.extend(quote!(ctx, )),
+            FnArg::Typed(arg) => {
+                let arg_name = arg_name(index, arg);
+                match shared_ref_elem(&arg.ty) {
+                    Some(elem) => args.extend(quote!(std::borrow::Borrow::<#elem>::borrow(&#arg_name), )),
+                    None => args.extend(quote!(#arg_name, )),
+                }
+            }
+            FnArg::Receiver(_) => (),
+        }
+    }
+    args
+}
+
+/// Whether an argument is the actor's context, which the router supplies instead of the caller
+fn is_context_arg(arg: &syn::PatType) -> bool {
+    has_actor_attr(&arg.attrs, "context")
+}
+
+/// Marks the arguments that are a `&{Type}Context` or `&mut {Type}Context` of this very actor with
+/// `#[actor(context)]`, other types named `..Context` are left to the caller
+fn mark_context_args(mut items: Vec<ImplItem>, context_ty: &syn::Ident) -> Vec<ImplItem> {
+    for item in items.iter_mut() {
+        if let ImplItem::Method(method) = item {
+            for arg in method.sig.inputs.iter_mut() {
+                if let FnArg::Typed(arg) = arg {
+                    let is_context = match &*arg.ty {
+                        syn::Type::Reference(reference) => match &*reference.elem {
+                            syn::Type::Path(path) => path.qself.is_none() && path.path.segments.last()
+                                .map_or(false, |segment| segment.ident == *context_ty),
+                            _ => false,
+                        },
+                        _ => false,
+                    };
+                    if is_context {
+                        arg.attrs.push(syn::parse_quote!(#[actor(context)]));
+                    }
+                }
+            }
+        }
+    }
+    items
+}
+
+/// How a message method answers its caller
+enum Reply {
+    /// Fire-and-forget, the caller doesn't wait for the message to be handled
+    None,
+    /// Request/response, the handler's return value is sent back over a oneshot
+    Value(syn::Type),
+    /// The handler returns a stream, whose items are sent back over a bounded channel
+    Stream(syn::Type),
+}
+
+fn reply(sig: &syn::Signature) -> Reply {
+    match &sig.output {
+        syn::ReturnType::Default => Reply::None,
+        syn::ReturnType::Type(_, ty) => match &**ty {
+            syn::Type::Tuple(tuple) if tuple.elems.is_empty() => Reply::None,
+            ty => match stream_item_ty(ty) {
+                Some(item_ty) => Reply::Stream(item_ty),
+                None => Reply::Value(ty.clone()),
+            },
+        },
+    }
+}
+
+/// Whether the handler returns `Result<(), E>`, which may be sent without a `reply_to`. The
+/// errors nobody waits on go to the actor's error policy instead
+fn fallible(sig: &syn::Signature) -> bool {
+    let ty = match &sig.output {
+        syn::ReturnType::Type(_, ty) => ty,
+        syn::ReturnType::Default => return false,
+    };
+    let segment = match &**ty {
+        syn::Type::Path(path) => path.path.segments.last(),
+        _ => None,
+    };
+
+    match segment {
+        Some(segment) if segment.ident == "Result" => match &segment.arguments {
+            syn::PathArguments::AngleBracketed(generic_args) => {
+                generic_args.args.len() == 2 && match generic_args.args.first() {
+                    Some(syn::GenericArgument::Type(syn::Type::Tuple(tuple))) => tuple.elems.is_empty(),
+                    _ => false,
+                }
+            }
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// The item type of an `impl Stream<Item = T>` or a `BoxStream<'_, T>`
+fn stream_item_ty(ty: &syn::Type) -> Option<syn::Type> {
+    let generic_args = match ty {
+        syn::Type::ImplTrait(impl_trait) => impl_trait.bounds.iter().find_map(|bound| match bound {
+            syn::TypeParamBound::Trait(bound) => bound.path.segments.last()
+                .filter(|segment| segment.ident == "Stream")
+                .map(|segment| segment.arguments.clone()),
+            _ => None,
+        }),
+        syn::Type::Path(path) => path.path.segments.last()
+            .filter(|segment| segment.ident == "BoxStream")
+            .map(|segment| segment.arguments.clone()),
+        _ => None,
+    };
+
+    match generic_args? {
+        syn::PathArguments::AngleBracketed(generic_args) => {
+            generic_args.args.iter().find_map(|arg| match arg {
+                syn::GenericArgument::Binding(binding) if binding.ident == "Item" => Some(binding.ty.clone()),
+                syn::GenericArgument::Type(ty) => Some(ty.clone()),
+                _ => None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The argument marked `#[actor(stream)]`, which callers pass as a stream of items
+fn stream_arg(sig: &syn::Signature) -> Option<(syn::Ident, syn::Type)> {
+    sig.inputs.iter().enumerate().find_map(|(index, arg)| match arg {
+        FnArg::Typed(arg) if has_actor_attr(&arg.attrs, "stream") => Some((arg_name(index, arg), *arg.ty.clone())),
+        _ => None,
+    })
+}
+
+/// Whether a function takes `self` in any form, functions that don't aren't messages
+fn has_receiver(sig: &syn::Signature) -> bool {
+    match sig.inputs.first() {
+        Some(FnArg::Receiver(_)) => true,
+        Some(FnArg::Typed(arg)) => is_self_arg(arg),
+        None => false,
+    }
+}
+
+/// How a method takes `self`
+#[derive(PartialEq)]
+enum SelfReceiver {
+    /// `&self` or `&mut self`
+    Ref,
+    /// `self`, the handler consumes the ActorImpl
+    Owned,
+    /// `self: Box<Self>`
+    Boxed,
+}
+
+fn self_receiver(sig: &syn::Signature) -> SelfReceiver {
+    match sig.inputs.first() {
+        Some(FnArg::Receiver(receiver)) if receiver.reference.is_none() => SelfReceiver::Owned,
+        Some(FnArg::Typed(arg)) if is_self_arg(arg) => match &*arg.ty {
+            syn::Type::Reference(_) => SelfReceiver::Ref,
+            syn::Type::Path(path) if path.path.segments.last().map_or(false, |segment| segment.ident == "Box") => {
+                SelfReceiver::Boxed
+            }
+            _ => SelfReceiver::Owned,
+        },
+        _ => SelfReceiver::Ref,
+    }
+}
+
+/// Whether an argument is a receiver with an explicit type, like `self: Box<Self>`
+fn is_self_arg(arg: &syn::PatType) -> bool {
+    match &*arg.pat {
+        syn::Pat::Ident(pat) => pat.ident == "self",
+        _ => false,
+    }
+}
+
+/// Whether `attrs` contains `#[actor(.., name, ..)]`
+fn has_actor_attr(attrs: &[syn::Attribute], name: &str) -> bool {
+    attrs.iter()
+        .filter(|attr| attr.path.is_ident("actor"))
+        .filter_map(|attr| attr.parse_meta().ok())
+        .any(|meta| match meta {
+            syn::Meta::List(list) => list.nested.iter().any(|nested| match nested {
+                syn::NestedMeta::Meta(meta) => meta.path().is_ident(name),
+                _ => false,
+            }),
+            _ => false,
+        })
+}
+
+/// Removes the `#[actor(..)]` attributes, which only mean something to this macro
+fn strip_actor_attrs(item_impl: &mut syn::ItemImpl) {
+    for item in item_impl.items.iter_mut() {
+        if let ImplItem::Method(method) = item {
+            method.attrs.retain(|attr| !attr.path.is_ident("actor"));
+            for arg in method.sig.inputs.iter_mut() {
+                if let FnArg::Typed(arg) = arg {
+                    arg.attrs.retain(|attr| !attr.path.is_ident("actor"));
                 }
             }
         }
     }
+}
+
+fn all_generics(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote::ToTokens {
+    let all_generics = merged_generics(items, item_impl);
 
     let (impl_generics, ty_generics, where_clause) = all_generics.split_for_impl();
     let all_generics = quote!(#impl_generics);
@@ -405,6 +3689,17 @@ fn all_generics(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote::T
 
 
 fn all_generic_tys(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote::ToTokens {
+    let all_generics = merged_generics(items, item_impl);
+
+    let (impl_generics, ty_generics, where_clause) = all_generics.split_for_impl();
+    let all_generics = quote!(#ty_generics);
+    // println!("all_generic_tys {}", all_generics.to_string());
+
+    all_generics
+}
+
+// The impl block's generics, followed by the generics of every message method
+fn merged_generics(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> syn::Generics {
     let impl_generics = item_impl.generics;
 
     let mut all_generics = impl_generics.clone();
@@ -413,7 +3708,6 @@ fn all_generic_tys(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote
         if let ImplItem::Method(method) = item {
             if let Visibility::Public(vis) = method.vis {
                 let mut generics = method.sig.generics;
-                let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
                 for param in generics.params {
                     all_generics.params.push(param);
@@ -422,13 +3716,43 @@ fn all_generic_tys(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote
         }
     }
 
-    let (impl_generics, ty_generics, where_clause) = all_generics.split_for_impl();
-    let all_generics = quote!(#ty_generics);
-    // println!("all_generic_tys {}", all_generics.to_string());
-
     all_generics
 }
 
+// Only the params of `generics` that are mentioned by one of `tys`
+fn used_generics(generics: &syn::Generics, tys: &[syn::Type]) -> syn::Generics {
+    let words: Vec<String> = tys.iter()
+        .map(|ty| quote!(#ty).to_string())
+        .flat_map(|ty| {
+            ty.split(|c: char| !(c.is_alphanumeric() || c == '_' || c == '\''))
+                .map(String::from)
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut used = generics.clone();
+    used.params = generics.params.iter()
+        .filter(|param| {
+            let name = match param {
+                GenericParam::Type(param) => param.ident.to_string(),
+                GenericParam::Lifetime(param) => param.lifetime.to_string(),
+                GenericParam::Const(param) => param.ident.to_string(),
+            };
+            words.contains(&name)
+        })
+        .cloned()
+        .collect();
+
+    used
+}
+
+fn camel_case(s: &str) -> String {
+    s.split('_')
+        .filter(|part| !part.is_empty())
+        .map(capitalize)
+        .collect()
+}
+
 fn all_generic_tys_tuple(items: Vec<ImplItem>, item_impl: syn::ItemImpl) -> impl quote::ToTokens {
     let impl_generics = item_impl.generics;
 